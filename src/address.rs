@@ -0,0 +1,120 @@
+/*
+ ** Copyright (C) 2021-2024 KunoiSayami
+ **
+ ** This file is part of passive-DDNS and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+pub(crate) mod api {
+    use crate::configparser::{CurrentIP, IPSource};
+    use anyhow::anyhow;
+    use ipnet::IpNet;
+    use log::error;
+    use std::net::IpAddr;
+
+    /// Ranges never routed on public internet: private, CGNAT, loopback,
+    /// link-local, documentation, benchmarking, multicast and reserved
+    const REJECTED_RANGES: &[&str] = &[
+        "0.0.0.0/8",
+        "10.0.0.0/8",
+        "100.64.0.0/10",
+        "127.0.0.0/8",
+        "169.254.0.0/16",
+        "172.16.0.0/12",
+        "192.0.0.0/24",
+        "192.0.2.0/24",
+        "192.168.0.0/16",
+        "198.18.0.0/15",
+        "198.51.100.0/24",
+        "203.0.113.0/24",
+        "224.0.0.0/4",
+        "240.0.0.0/4",
+        "::/128",
+        "::1/128",
+        "::ffff:0:0/96",
+        "64:ff9b:1::/48",
+        "100::/64",
+        "2001:db8::/32",
+        "fc00::/7",
+        "fe80::/10",
+        "ff00::/8",
+    ];
+
+    /// Reject addresses which should never be published
+    pub struct AddressFilter {
+        rejected: Vec<IpNet>,
+        allowed: Vec<IpNet>,
+    }
+
+    impl AddressFilter {
+        /// Addresses in `allowed` are accepted even if in a rejected range
+        pub fn new(allowed: Vec<IpNet>) -> Self {
+            Self {
+                rejected: REJECTED_RANGES
+                    .iter()
+                    .map(|range| range.parse().unwrap())
+                    .collect(),
+                allowed,
+            }
+        }
+
+        pub fn is_acceptable(&self, address: IpAddr) -> bool {
+            self.allowed.iter().any(|range| range.contains(&address))
+                || !self.rejected.iter().any(|range| range.contains(&address))
+        }
+
+        fn drop_rejected(&self, current: &mut CurrentIP) {
+            if let Some(v4) = current.v4.filter(|v4| !self.is_acceptable((*v4).into())) {
+                error!("Refuse to publish reserved address {v4}");
+                current.v4 = None;
+            }
+            if let Some(v6) = current.v6.filter(|v6| !self.is_acceptable((*v6).into())) {
+                error!("Refuse to publish reserved address {v6}");
+                current.v6 = None;
+            }
+            for interface in current.interfaces.values_mut() {
+                self.drop_rejected(interface);
+            }
+        }
+
+        /// Drop rejected addresses, error if nothing is left
+        pub fn filter(&self, mut current: CurrentIP) -> anyhow::Result<CurrentIP> {
+            self.drop_rejected(&mut current);
+            if current.is_empty() && current.interfaces.values().all(CurrentIP::is_empty) {
+                return Err(anyhow!("No acceptable IP address"));
+            }
+            Ok(current)
+        }
+    }
+
+    /// Validate addresses of inner source before use
+    pub struct FilteredIPSource {
+        inner: Box<dyn IPSource>,
+        filter: AddressFilter,
+    }
+
+    impl FilteredIPSource {
+        pub fn new(inner: Box<dyn IPSource>, filter: AddressFilter) -> Self {
+            Self { inner, filter }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl IPSource for FilteredIPSource {
+        async fn get_current_ip(&self) -> anyhow::Result<CurrentIP> {
+            self.filter.filter(self.inner.get_current_ip().await?)
+        }
+    }
+}
@@ -0,0 +1,92 @@
+/*
+ ** Copyright (C) 2021-2024 KunoiSayami
+ **
+ ** This file is part of passive-DDNS and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use rand::Rng;
+use std::time::Duration;
+
+/// Exponential backoff with jitter, used when name server or IP source failed
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max_delay: Duration,
+    multiplier: f64,
+    /// Fraction of delay to randomize, 0.2 means ±20%
+    jitter: f64,
+    /// Exit if still failing after this duration
+    give_up_after: Option<Duration>,
+    give_up_exit_code: i32,
+}
+
+impl Backoff {
+    pub fn new(
+        initial: Duration,
+        max_delay: Duration,
+        multiplier: f64,
+        jitter: f64,
+        give_up_after: Option<Duration>,
+        give_up_exit_code: i32,
+    ) -> Self {
+        Self {
+            initial,
+            max_delay,
+            multiplier: multiplier.max(1.0),
+            jitter: jitter.clamp(0.0, 1.0),
+            give_up_after,
+            give_up_exit_code,
+        }
+    }
+
+    /// Delay before next attempt, `attempt` starts from 1
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let base = self.initial.as_secs_f64()
+            * self
+                .multiplier
+                .powi(attempt.saturating_sub(1).min(i32::MAX as u32) as i32);
+        let base = base.min(self.max_delay.as_secs_f64());
+        let jitter = if self.jitter > 0.0 {
+            rand::thread_rng().gen_range(-self.jitter..=self.jitter)
+        } else {
+            0.0
+        };
+        Duration::from_secs_f64((base * (1.0 + jitter)).clamp(0.0, self.max_delay.as_secs_f64()))
+    }
+
+    /// Whether failing since `since` is long enough to give up
+    pub fn should_give_up(&self, since: std::time::Instant) -> bool {
+        self.give_up_after
+            .is_some_and(|give_up_after| since.elapsed() >= give_up_after)
+    }
+
+    pub fn give_up_exit_code(&self) -> i32 {
+        self.give_up_exit_code
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(
+            Duration::from_secs(5),
+            Duration::from_secs(600),
+            2.0,
+            0.2,
+            None,
+            75,
+        )
+    }
+}
@@ -17,22 +17,47 @@
  ** You should have received a copy of the GNU Affero General Public License
  ** along with this program. If not, see <https://www.gnu.org/licenses/>.
  */
-const DEFAULT_TIMEOUT: u64 = 10;
+const API_BASE: &str = "https://api.cloudflare.com/client/v4";
 pub(crate) mod api {
-    use super::DEFAULT_TIMEOUT;
-    use crate::configparser::NameServer;
+    use super::error::{decode, Error};
+    use super::API_BASE;
+    use crate::configparser::DEFAULT_TIMEOUT;
+    use crate::configparser::{CurrentIP, NameServer, RecordType, UpdateOutcome, UpdatedRecord};
+    use anyhow::anyhow;
+    use log::{error, info, warn};
     use serde::{Deserialize, Serialize};
     use std::collections::HashMap;
     use std::time::Duration;
+    use tap::TapFallible;
 
     #[derive(Deserialize)]
     pub(crate) struct DNSRecord {
         id: String,
         zone_id: String,
         name: String,
+        #[serde(rename = "type")]
+        t: RecordType,
         content: String,
         proxied: bool,
         ttl: i32,
+        comment: Option<String>,
+        #[serde(default)]
+        tags: Vec<String>,
+        #[serde(skip)]
+        state: RecordState,
+    }
+
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub(crate) enum RecordState {
+        /// Record already matches desired state
+        #[default]
+        Synced,
+        /// Record is created in this round
+        Created,
+        /// Record is updated by known id in this round
+        Patched,
+        /// Record need to be updated
+        Outdated,
     }
 
     impl DNSRecord {
@@ -41,96 +66,544 @@ pub(crate) mod api {
             self.content = content.into();
         }*/
 
-        async fn update_ns_record(&self, session: &reqwest::Client) -> reqwest::Result<bool> {
+        #[cfg(test)]
+        pub(crate) fn state(&self) -> RecordState {
+            self.state
+        }
+
+        async fn update_ns_record(
+            &self,
+            session: &reqwest::Client,
+            api: &str,
+        ) -> Result<(), Error> {
             let resp = session
                 .put(format!(
-                    "https://api.cloudflare.com/client/v4/zones/{}/dns_records/{}",
+                    "{api}/zones/{}/dns_records/{}",
                     self.zone_id, self.id
                 ))
                 .json(&PutDNSRecord::from_dns_record(self))
                 .send()
                 .await?;
-            Ok(resp.status().is_success())
+            decode::<serde_json::Value>(resp).await?;
+            Ok(())
         }
     }
 
     #[derive(Serialize)]
     struct PutDNSRecord {
         #[serde(rename = "type")]
-        t: String,
+        t: RecordType,
         name: String,
         content: String,
         proxied: bool,
         ttl: i32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        comment: Option<String>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        tags: Vec<String>,
+    }
+
+    /// Content and declared settings of a record, updated by known id
+    #[derive(Serialize)]
+    struct PatchDNSRecord<'a> {
+        content: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        ttl: Option<i32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        proxied: Option<bool>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        comment: Option<&'a String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tags: Option<&'a Vec<String>>,
+    }
+
+    impl<'a> PatchDNSRecord<'a> {
+        fn new(domain: &'a Domain, content: &'a str) -> Self {
+            Self {
+                content,
+                ttl: domain.ttl,
+                proxied: domain.proxied,
+                comment: domain.comment.as_ref(),
+                tags: domain.tags.as_ref(),
+            }
+        }
+    }
+
+    /// Fields used when a record has to be created
+    #[derive(Clone, Debug)]
+    pub struct RecordDefaults {
+        auto_create: bool,
+        ttl: i32,
+        proxied: bool,
+    }
+
+    impl Default for RecordDefaults {
+        fn default() -> Self {
+            Self {
+                auto_create: true,
+                ttl: 1,
+                proxied: false,
+            }
+        }
     }
 
     impl PutDNSRecord {
+        fn new(
+            record_type: RecordType,
+            domain: &Domain,
+            content: &str,
+            defaults: &RecordDefaults,
+        ) -> PutDNSRecord {
+            PutDNSRecord {
+                t: record_type,
+                name: domain.name.clone(),
+                content: content.to_string(),
+                proxied: domain.proxied.unwrap_or(defaults.proxied),
+                ttl: domain.ttl.unwrap_or(defaults.ttl),
+                comment: domain.comment.clone(),
+                tags: domain.tags.clone().unwrap_or_default(),
+            }
+        }
+
         fn from_dns_record(dns_record: &DNSRecord) -> PutDNSRecord {
             PutDNSRecord {
-                t: String::from('A'),
+                t: dns_record.t,
                 name: String::from(&dns_record.name),
                 content: String::from(&dns_record.content),
                 proxied: dns_record.proxied,
                 ttl: dns_record.ttl,
+                comment: dns_record.comment.clone(),
+                tags: dns_record.tags.clone(),
             }
         }
     }
 
+    /// Desired state of a hostname, settings not declared are kept as-is on Cloudflare
+    #[derive(Deserialize, Clone, Debug)]
+    pub struct Domain {
+        name: String,
+        ttl: Option<i32>,
+        proxied: Option<bool>,
+        comment: Option<String>,
+        tags: Option<Vec<String>>,
+        types: Option<Vec<RecordType>>,
+        /// Publish address of this WAN interface instead of the default one
+        interface: Option<String>,
+    }
+
+    /// Domain can be written as hostname only, or as a table with settings
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum DomainEntry {
+        Name(String),
+        Detail(Domain),
+    }
+
+    impl From<DomainEntry> for Domain {
+        fn from(entry: DomainEntry) -> Self {
+            match entry {
+                DomainEntry::Name(name) => Domain {
+                    name,
+                    ttl: None,
+                    proxied: None,
+                    comment: None,
+                    tags: None,
+                    types: None,
+                    interface: None,
+                },
+                DomainEntry::Detail(domain) => domain,
+            }
+        }
+    }
+
+    fn deserialize_domains<'de, D>(deserializer: D) -> Result<Vec<Domain>, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        Ok(Vec::<DomainEntry>::deserialize(deserializer)?
+            .into_iter()
+            .map(Domain::from)
+            .collect())
+    }
+
+    impl Domain {
+        pub(crate) fn name(&self) -> &str {
+            &self.name
+        }
+
+        pub(crate) fn interface(&self) -> Option<&str> {
+            self.interface.as_deref()
+        }
+
+        /// Desired settings of record `record_type`, changes once configure changed
+        fn fingerprint(&self, record_type: RecordType) -> String {
+            format!(
+                "{record_type} {} {:?} {:?} {:?} {:?} {:?}",
+                self.name, self.ttl, self.proxied, self.comment, self.tags, self.interface
+            )
+        }
+
+        /// Apply desired state to record, return true if anything changed
+        pub(crate) fn reconcile(&self, record: &mut DNSRecord, content: &str) -> bool {
+            let mut changed = false;
+            if record.content != content {
+                record.content = content.to_string();
+                changed = true;
+            }
+            if let Some(ttl) = self.ttl.filter(|ttl| *ttl != record.ttl) {
+                record.ttl = ttl;
+                changed = true;
+            }
+            if let Some(proxied) = self.proxied.filter(|proxied| *proxied != record.proxied) {
+                record.proxied = proxied;
+                changed = true;
+            }
+            if let Some(comment) = &self.comment {
+                if record.comment.as_ref() != Some(comment) {
+                    record.comment = Some(comment.clone());
+                    changed = true;
+                }
+            }
+            if let Some(tags) = &self.tags {
+                let mut expected = tags.clone();
+                let mut current = record.tags.clone();
+                expected.sort();
+                current.sort();
+                if expected != current {
+                    record.tags = tags.clone();
+                    changed = true;
+                }
+            }
+            changed
+        }
+    }
+
     #[derive(Deserialize, Clone, Debug)]
     pub struct Zone {
-        zone_id: String,
-        domains: Vec<String>,
+        zone_id: Option<String>,
+        zone_name: Option<String>,
+        #[serde(deserialize_with = "deserialize_domains")]
+        domains: Vec<Domain>,
+        types: Option<Vec<RecordType>>,
+        /// Token used by this zone only
+        token: Option<String>,
+        /// Name of token in `[cloudflare.credentials]`
+        credential: Option<String>,
+    }
+
+    /// API tokens of Cloudflare accounts
+    #[derive(Clone, Debug, Default)]
+    pub struct Credentials {
+        default: Option<String>,
+        named: HashMap<String, String>,
+    }
+
+    impl Credentials {
+        pub fn new(default: Option<String>, named: HashMap<String, String>) -> Self {
+            Self { default, named }
+        }
+
+        /// Token of zone, zone token > named credential > default token
+        fn token_of<'a>(&'a self, zone: &'a Zone) -> anyhow::Result<&'a str> {
+            if let Some(token) = &zone.token {
+                return Ok(token);
+            }
+            match &zone.credential {
+                Some(name) => self
+                    .named
+                    .get(name)
+                    .map(String::as_str)
+                    .ok_or_else(|| anyhow!("Credential {name} is not defined")),
+                None => self
+                    .default
+                    .as_deref()
+                    .ok_or_else(|| anyhow!("No token for zone {:?}", zone.domain_names())),
+            }
+        }
     }
 
     #[cfg(test)]
     impl Zone {
-        pub(crate) fn zone_id(&self) -> &str {
-            &self.zone_id
-        }
-        pub(crate) fn domains(&self) -> &Vec<String> {
+        pub(crate) fn domains(&self) -> &Vec<Domain> {
             &self.domains
         }
     }
 
+    /// Possible zone names of a hostname, from the longest to the shortest one,
+    /// e.g. `a.example.com` gives `a.example.com` and `example.com`
+    pub(crate) fn zone_name_candidates(domain: &str) -> Vec<String> {
+        let labels = domain.trim_end_matches('.').split('.').collect::<Vec<_>>();
+        (0..labels.len().saturating_sub(1))
+            .map(|i| labels[i..].join("."))
+            .collect()
+    }
+
     impl Zone {
+        pub(crate) fn zone_id(&self) -> Option<&str> {
+            self.zone_id.as_deref()
+        }
+
+        /// Default is A record only, can be overridden by each domain
+        pub(crate) fn types(&self, domain: &Domain) -> Vec<RecordType> {
+            domain
+                .types
+                .clone()
+                .or_else(|| self.types.clone())
+                .unwrap_or_else(|| vec![RecordType::A])
+        }
+
+        fn domain_names(&self) -> Vec<&str> {
+            self.domains.iter().map(Domain::name).collect()
+        }
+
+        /// Reject hostnames not belonging to zone `zone_name`
+        fn check_domains(&self, zone_name: &str) -> anyhow::Result<()> {
+            let zone_name = zone_name.trim_end_matches('.');
+            for domain in &self.domains {
+                let name = domain.name().trim_end_matches('.');
+                if name != zone_name && !name.ends_with(&format!(".{zone_name}")) {
+                    return Err(anyhow!(
+                        "{name} is not under zone {zone_name}, put it into another [[cloudflare.domain]]"
+                    ));
+                }
+            }
+            Ok(())
+        }
+
+        async fn create_domain_record(
+            api: &str,
+            zone_id: &str,
+            session: &reqwest::Client,
+            record: PutDNSRecord,
+        ) -> Result<Option<DNSRecord>, Error> {
+            let resp = session
+                .post(format!("{api}/zones/{zone_id}/dns_records"))
+                .json(&record)
+                .send()
+                .await?;
+            let dns_record = decode::<DNSRecord>(resp)
+                .await
+                .tap_err(|e| {
+                    if let Error::Auth { .. } = e {
+                        error!(
+                            "Token is not permitted to create {} record {}, grant `Zone.DNS:Edit` or create it manually: {e}",
+                            record.t, record.name
+                        )
+                    }
+                })?;
+            info!(
+                "Created {} record {} -> {}",
+                record.t, record.name, record.content
+            );
+            Ok(dns_record.map(|mut dns_record| {
+                dns_record.state = RecordState::Created;
+                dns_record
+            }))
+        }
+
+        async fn query_domain_record(
+            api: &str,
+            zone_id: &str,
+            session: &reqwest::Client,
+            domain: &Domain,
+            type_name: &str,
+        ) -> Result<Option<DNSRecord>, Error> {
+            let query: HashMap<&str, &str> = [("type", type_name), ("name", domain.name())]
+                .iter()
+                .cloned()
+                .collect();
+            let resp = session
+                .get(format!("{api}/zones/{zone_id}/dns_records").as_str())
+                .query(&query)
+                .send()
+                .await?;
+            let result: Vec<DNSRecord> = decode(resp).await?.unwrap_or_default();
+            Ok(result.into_iter().next())
+        }
+
+        /// Update content and declared settings of record `id` without querying it
+        async fn patch_domain_record(
+            api: &str,
+            zone_id: &str,
+            session: &reqwest::Client,
+            id: &str,
+            record: PatchDNSRecord<'_>,
+        ) -> Result<Option<DNSRecord>, Error> {
+            let resp = session
+                .patch(format!("{api}/zones/{zone_id}/dns_records/{id}"))
+                .json(&record)
+                .send()
+                .await?;
+            Ok(decode::<DNSRecord>(resp).await?.map(|mut dns_record| {
+                dns_record.state = RecordState::Patched;
+                dns_record
+            }))
+        }
+
+        /// Query records of all domains, failed ones are collected into `failed`.
+        /// Records in `known` are published before, they are updated by id directly.
         pub(crate) async fn request_domain_record(
             &self,
             session: &reqwest::Client,
-        ) -> Result<Vec<DNSRecord>, reqwest::Error> {
+            api: &str,
+            current_ip: &CurrentIP,
+            known: &[UpdatedRecord],
+            defaults: &RecordDefaults,
+            failed: &mut Vec<(String, crate::error::Error)>,
+        ) -> Vec<DNSRecord> {
             let mut records: Vec<DNSRecord> = Default::default();
+            let Some(zone_id) = self.zone_id() else {
+                failed.extend(self.domain_names().into_iter().map(|name| {
+                    (
+                        name.to_string(),
+                        anyhow!("Zone id of {name} is not resolved").into(),
+                    )
+                }));
+                return records;
+            };
             //let form: HashMap::<_, _>::from_iter = (("test", "test"), ("test", "test"));
 
-            for domain in &self.domains {
-                let query: HashMap<&str, &str> =
-                    [("type", "A"), ("name", &domain)].iter().cloned().collect();
-                let resp = session
-                    .get(
-                        format!(
-                            "https://api.cloudflare.com/client/v4/zones/{}/dns_records",
-                            &self.zone_id
+            for (domain, record_type) in self
+                .domains
+                .iter()
+                .flat_map(|domain| self.types(domain).into_iter().map(move |t| (domain, t)))
+            {
+                let Some(address) = current_ip.get_on(domain.interface(), record_type) else {
+                    let e = match domain.interface() {
+                        Some(interface) => {
+                            anyhow!("No {record_type} address on interface {interface}")
+                        }
+                        None => anyhow!("No {record_type} address available"),
+                    };
+                    failed.push((format!("{record_type} {}", domain.name), e.into()));
+                    continue;
+                };
+                let content = address.to_string();
+                let type_name = record_type.to_string();
+                if let Some(published) = known.iter().find(|record| {
+                    record.record_type == record_type
+                        && record
+                            .name
+                            .eq_ignore_ascii_case(domain.name().trim_end_matches('.'))
+                }) {
+                    if published.content == content {
+                        continue;
+                    }
+                    if let Some(id) = &published.id {
+                        match Self::patch_domain_record(
+                            api,
+                            zone_id,
+                            session,
+                            id,
+                            PatchDNSRecord::new(domain, &content),
                         )
-                        .as_str(),
-                    )
-                    .query(&query)
-                    .send()
-                    .await?;
-                let resp_json: serde_json::Value = resp.json().await.unwrap();
-                let dns_record: DNSRecord =
-                    serde_json::from_value(resp_json["result"][0].to_owned()).unwrap();
-                records.push(dns_record);
+                        .await
+                        {
+                            Ok(Some(dns_record)) => {
+                                records.push(dns_record);
+                                continue;
+                            }
+                            Ok(None) => {}
+                            Err(Error::NotFound { .. }) => {
+                                warn!("{type_name} record {} is removed, query again", domain.name)
+                            }
+                            Err(e) => {
+                                error!("Update {type_name} record {} error: {e}", domain.name);
+                                failed.push((format!("{type_name} {}", domain.name), e.into()));
+                                continue;
+                            }
+                        }
+                    }
+                }
+                let result = match Self::query_domain_record(
+                    api, zone_id, session, domain, &type_name,
+                )
+                .await
+                {
+                    Ok(result) => result,
+                    Err(e) => {
+                        error!("Query {type_name} record {} error: {e}", domain.name);
+                        failed.push((format!("{type_name} {}", domain.name), e.into()));
+                        continue;
+                    }
+                };
+                match result {
+                    Some(mut dns_record) => {
+                        if domain.reconcile(&mut dns_record, &content) {
+                            dns_record.state = RecordState::Outdated;
+                        }
+                        records.push(dns_record)
+                    }
+                    None if defaults.auto_create => {
+                        info!("{type_name} record {} not found, creating", domain.name);
+                        match Self::create_domain_record(
+                            api,
+                            zone_id,
+                            session,
+                            PutDNSRecord::new(record_type, domain, &content, defaults),
+                        )
+                        .await
+                        {
+                            Ok(Some(dns_record)) => records.push(dns_record),
+                            Ok(None) => failed.push((
+                                format!("{type_name} {}", domain.name),
+                                anyhow!("Created record is not returned").into(),
+                            )),
+                            Err(e) => {
+                                failed.push((format!("{type_name} {}", domain.name), e.into()))
+                            }
+                        }
+                    }
+                    None => failed.push((
+                        format!("{type_name} {}", domain.name),
+                        anyhow!("Record not found and auto create is disabled").into(),
+                    )),
+                }
             }
-            Ok(records)
+            records
         }
     }
 
     pub struct Configure {
         zones: Vec<Zone>,
-        session: reqwest::Client,
+        credentials: Credentials,
+        /// One session per token
+        sessions: HashMap<String, reqwest::Client>,
+        defaults: RecordDefaults,
+        /// Zones with zone id, resolved on first update
+        resolved: tokio::sync::OnceCell<Vec<Zone>>,
+        /// Base URL of Cloudflare API
+        api: String,
     }
 
     impl Configure {
-        pub fn new<T: Into<String>>(domains: Vec<Zone>, api_token: T) -> Configure {
-            let api_token = api_token.into();
+        pub fn new(
+            domains: Vec<Zone>,
+            credentials: Credentials,
+            defaults: RecordDefaults,
+        ) -> anyhow::Result<Configure> {
+            let mut sessions = HashMap::new();
+            for zone in &domains {
+                if let Some(zone_name) = &zone.zone_name {
+                    zone.check_domains(zone_name)?;
+                }
+                let token = credentials.token_of(zone)?;
+                if !sessions.contains_key(token) {
+                    sessions.insert(token.to_string(), Self::build_session(token));
+                }
+            }
+
+            Ok(Configure {
+                zones: domains.clone(),
+                credentials,
+                sessions,
+                defaults,
+                resolved: Default::default(),
+                api: API_BASE.to_string(),
+            })
+        }
+
+        fn build_session(api_token: &str) -> reqwest::Client {
             let mut header_map = reqwest::header::HeaderMap::new();
             header_map.insert(
                 "Authorization",
@@ -139,25 +612,109 @@ pub(crate) mod api {
             header_map.insert("Content-Type", "application/json".parse().unwrap());
             header_map.insert("Connection", "close".parse().unwrap());
 
-            let session = reqwest::Client::builder()
+            reqwest::Client::builder()
                 .default_headers(header_map)
                 .timeout(Duration::from_secs(DEFAULT_TIMEOUT))
                 .connect_timeout(Duration::from_secs(DEFAULT_TIMEOUT))
                 .build()
-                .unwrap();
+                .unwrap()
+        }
 
-            Configure {
-                zones: domains.clone(),
-                session,
+        fn session(&self, zone: &Zone) -> &reqwest::Client {
+            // Every token is checked in `Configure::new`
+            &self.sessions[self.credentials.token_of(zone).unwrap()]
+        }
+
+        /// Query zone id by zone name, requires `Zone:Read` permission
+        async fn query_zone_id(
+            session: &reqwest::Client,
+            api: &str,
+            zone_name: &str,
+        ) -> Result<Option<String>, Error> {
+            #[derive(Deserialize)]
+            struct ZoneResult {
+                id: String,
             }
+
+            let resp = session
+                .get(format!("{api}/zones"))
+                .query(&[("name", zone_name)])
+                .send()
+                .await?;
+            let result: Vec<ZoneResult> = decode(resp).await?.unwrap_or_default();
+            Ok(result.into_iter().next().map(|zone| zone.id))
+        }
+
+        /// Fill zone id of zones which only specified zone name or hostnames,
+        /// zones sharing the same name are only queried once.
+        /// Request errors can be retried, missing zone is a configure error.
+        async fn resolve_zones(&self) -> crate::error::Result<Vec<Zone>> {
+            let mut zones = self.zones.clone();
+            let mut cache: HashMap<String, String> = Default::default();
+            for zone in &mut zones {
+                if zone.zone_id.is_some() {
+                    continue;
+                }
+                let session = self.session(zone);
+                let candidates = match &zone.zone_name {
+                    Some(name) => vec![name.clone()],
+                    None => zone
+                        .domains
+                        .first()
+                        .map(|domain| zone_name_candidates(domain.name()))
+                        .unwrap_or_default(),
+                };
+                for name in candidates {
+                    let zone_id = match cache.get(&name) {
+                        Some(zone_id) => Some(zone_id.clone()),
+                        None => Self::query_zone_id(session, &self.api, &name).await?,
+                    };
+                    if let Some(zone_id) = zone_id {
+                        // Other hostnames may belong to another zone if zone name is inferred
+                        zone.check_domains(&name)
+                            .map_err(|e| crate::error::Error::Config(e.to_string()))?;
+                        info!("Resolved zone {name} to {zone_id}");
+                        cache.insert(name, zone_id.clone());
+                        zone.zone_id = Some(zone_id);
+                        break;
+                    }
+                }
+                if zone.zone_id.is_none() {
+                    return Err(crate::error::Error::Config(format!(
+                        "Unable to find zone of {:?}",
+                        zone.domain_names()
+                    )));
+                }
+            }
+            Ok(zones)
         }
 
-        async fn fetch_data(&self) -> Result<Vec<DNSRecord>, reqwest::Error> {
+        /// Fetch records with session of their zone
+        async fn fetch_data(
+            &self,
+            zones: &[Zone],
+            current_ip: &CurrentIP,
+            known: &[UpdatedRecord],
+            failed: &mut Vec<(String, crate::error::Error)>,
+        ) -> Vec<(DNSRecord, &reqwest::Client)> {
             let mut result = Vec::new();
-            for zone in &self.zones {
-                result.extend(zone.request_domain_record(&self.session).await?);
+            for zone in zones {
+                let session = self.session(zone);
+                result.extend(
+                    zone.request_domain_record(
+                        session,
+                        &self.api,
+                        current_ip,
+                        known,
+                        &self.defaults,
+                        failed,
+                    )
+                    .await
+                    .into_iter()
+                    .map(|record| (record, session)),
+                );
             }
-            Ok(result)
+            result
         }
 
         #[cfg(test)]
@@ -170,6 +727,10 @@ pub(crate) mod api {
     pub struct CloudFlareConfigure {
         enabled: Option<bool>,
         token: Option<String>,
+        credentials: Option<HashMap<String, String>>,
+        auto_create: Option<bool>,
+        default_ttl: Option<i32>,
+        default_proxied: Option<bool>,
         domain: Option<Vec<Zone>>,
     }
 
@@ -178,30 +739,222 @@ pub(crate) mod api {
         pub fn get_enabled(&self) -> bool {
             self.enabled.unwrap_or(true)
         }
-        pub fn get_token(&self) -> &Option<String> {
-            &self.token
+        pub fn get_credentials(&self) -> Credentials {
+            Credentials::new(
+                self.token.clone(),
+                self.credentials.clone().unwrap_or_default(),
+            )
         }
         pub fn get_domain(&self) -> &Option<Vec<Zone>> {
             &self.domain
         }
+        /// Whether any domain manages records of `record_type`
+        pub fn manages(&self, record_type: RecordType) -> bool {
+            self.domain.iter().flatten().any(|zone| {
+                zone.domains
+                    .iter()
+                    .any(|domain| zone.types(domain).contains(&record_type))
+            })
+        }
+        /// Create missing records with `default_ttl` (default 1, automatic)
+        /// and `default_proxied` (default false), enabled by default
+        pub fn get_record_defaults(&self) -> RecordDefaults {
+            let default = RecordDefaults::default();
+            RecordDefaults {
+                auto_create: self.auto_create.unwrap_or(default.auto_create),
+                ttl: self.default_ttl.unwrap_or(default.ttl),
+                proxied: self.default_proxied.unwrap_or(default.proxied),
+            }
+        }
     }
 
     #[async_trait::async_trait]
     impl NameServer for Configure {
-        async fn update_dns_result(&self, new_record: &str) -> Result<bool, reqwest::Error> {
+        fn name(&self) -> &str {
+            "cloudflare"
+        }
+
+        fn fingerprint(&self) -> String {
+            let mut fingerprint = format!("{:?}", self.defaults);
+            for zone in &self.zones {
+                fingerprint.push_str(&format!("\n{:?} {:?}", zone.zone_id, zone.zone_name));
+                for domain in &zone.domains {
+                    for record_type in zone.types(domain) {
+                        fingerprint.push_str(&format!("\n{}", domain.fingerprint(record_type)));
+                    }
+                }
+            }
+            fingerprint
+        }
+
+        async fn update_dns_result(
+            &self,
+            new_record: &CurrentIP,
+            known: &[UpdatedRecord],
+        ) -> crate::error::Result<UpdateOutcome> {
+            let mut updated = Vec::new();
             let mut need_updated = Vec::new();
-            for record in self.fetch_data().await? {
-                if !record.content.eq(new_record) {
-                    let mut mut_record = record;
-                    mut_record.content = String::from(new_record);
-                    need_updated.push(mut_record);
+            let zones = self
+                .resolved
+                .get_or_try_init(|| self.resolve_zones())
+                .await
+                .tap_err(|e| error!("Resolve cloudflare zone error: {e}"))?;
+            let mut failed = Vec::new();
+            for (record, session) in self.fetch_data(zones, new_record, known, &mut failed).await {
+                match record.state {
+                    RecordState::Synced => {}
+                    RecordState::Created | RecordState::Patched => updated.push(
+                        UpdatedRecord::new(&record.name, record.t, &record.content)
+                            .with_id(&record.id),
+                    ),
+                    RecordState::Outdated => need_updated.push((record, session)),
                 }
             }
-            let rt = !need_updated.is_empty();
-            for record in need_updated {
-                record.update_ns_record(&self.session).await?;
+            for (record, session) in need_updated {
+                match record.update_ns_record(session, &self.api).await {
+                    Ok(()) => updated.push(
+                        UpdatedRecord::new(&record.name, record.t, &record.content)
+                            .with_id(&record.id),
+                    ),
+                    Err(e) => failed.push((format!("{} {}", record.t, record.name), e.into())),
+                }
             }
-            Ok(rt)
+            Ok(match (updated.is_empty(), failed.is_empty()) {
+                (true, true) => UpdateOutcome::Unchanged,
+                (_, true) => UpdateOutcome::Updated(updated),
+                (_, false) => UpdateOutcome::PartialFailure { updated, failed },
+            })
+        }
+    }
+}
+
+pub(crate) mod error {
+    use reqwest::StatusCode;
+    use serde::Deserialize;
+
+    /// Entry of `errors` and `messages` in Cloudflare API response
+    #[derive(Clone, Debug, Deserialize)]
+    pub struct ApiMessage {
+        #[serde(default)]
+        code: i64,
+        #[serde(default)]
+        message: String,
+    }
+
+    impl std::fmt::Display for ApiMessage {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{}: {}", self.code, self.message)
+        }
+    }
+
+    #[derive(Clone, Debug, Default)]
+    pub struct ApiMessages(pub Vec<ApiMessage>);
+
+    impl std::fmt::Display for ApiMessages {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            let messages = self.0.iter().map(ToString::to_string).collect::<Vec<_>>();
+            write!(f, "[{}]", messages.join(", "))
+        }
+    }
+
+    /// Response envelope shared by every Cloudflare API v4 endpoint
+    #[derive(Deserialize)]
+    pub struct Envelope<T> {
+        #[serde(default)]
+        success: bool,
+        #[serde(default)]
+        errors: Vec<ApiMessage>,
+        #[serde(default)]
+        messages: Vec<ApiMessage>,
+        result: Option<T>,
+    }
+
+    impl<T> Envelope<T> {
+        pub fn messages(&self) -> &[ApiMessage] {
+            &self.messages
+        }
+
+        pub fn into_result(self, status: StatusCode) -> Result<Option<T>, Error> {
+            if self.success && status.is_success() {
+                return Ok(self.result);
+            }
+            Err(Error::from_response(status, self.errors))
+        }
+    }
+
+    #[derive(Debug, thiserror::Error)]
+    pub enum Error {
+        #[error("Cloudflare authentication failed ({status}): {errors}")]
+        Auth {
+            status: StatusCode,
+            errors: ApiMessages,
+        },
+        #[error("Cloudflare rate limit exceeded ({status}): {errors}")]
+        RateLimit {
+            status: StatusCode,
+            errors: ApiMessages,
+        },
+        #[error("Cloudflare resource not found ({status}): {errors}")]
+        NotFound {
+            status: StatusCode,
+            errors: ApiMessages,
+        },
+        #[error("Cloudflare validation failed ({status}): {errors}")]
+        Validation {
+            status: StatusCode,
+            errors: ApiMessages,
+        },
+        #[error("Cloudflare API error ({status}): {errors}")]
+        Api {
+            status: StatusCode,
+            errors: ApiMessages,
+        },
+        #[error("Request cloudflare error: {0}")]
+        Request(#[from] reqwest::Error),
+    }
+
+    impl Error {
+        pub fn from_response(status: StatusCode, errors: Vec<ApiMessage>) -> Self {
+            let codes = errors.iter().map(|e| e.code).collect::<Vec<_>>();
+            let errors = ApiMessages(errors);
+            // https://developers.cloudflare.com/fundamentals/api/troubleshooting/
+            if matches!(status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN)
+                || codes
+                    .iter()
+                    .any(|c| matches!(c, 9103 | 9106 | 9107 | 9109 | 10000))
+            {
+                Self::Auth { status, errors }
+            } else if status == StatusCode::TOO_MANY_REQUESTS
+                || codes.iter().any(|c| matches!(c, 971 | 10013))
+            {
+                Self::RateLimit { status, errors }
+            } else if status == StatusCode::NOT_FOUND
+                || codes.iter().any(|c| matches!(c, 7003 | 81044))
+            {
+                Self::NotFound { status, errors }
+            } else if matches!(
+                status,
+                StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY
+            ) || codes
+                .iter()
+                .any(|c| matches!(c, 1004 | 9005 | 9021 | 81053 | 81057))
+            {
+                Self::Validation { status, errors }
+            } else {
+                Self::Api { status, errors }
+            }
+        }
+    }
+
+    /// Decode response body as [`Envelope`] and check whether request is success
+    pub async fn decode<T: serde::de::DeserializeOwned>(
+        resp: reqwest::Response,
+    ) -> Result<Option<T>, Error> {
+        let status = resp.status();
+        let envelope: Envelope<T> = resp.json().await?;
+        for message in envelope.messages() {
+            log::debug!("Cloudflare message: {message}");
         }
+        envelope.into_result(status)
     }
 }
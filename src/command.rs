@@ -0,0 +1,124 @@
+/*
+ ** Copyright (C) 2021-2024 KunoiSayami
+ **
+ ** This file is part of passive-DDNS and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+pub(crate) mod api {
+    use crate::configparser::{CurrentIP, IPSource};
+    use anyhow::anyhow;
+    use log::{debug, warn};
+    use serde::Deserialize;
+    use std::collections::HashMap;
+    use std::net::IpAddr;
+    use std::time::Duration;
+
+    #[derive(Deserialize)]
+    pub struct CommandConfigure {
+        enabled: Option<bool>,
+        program: String,
+        args: Option<Vec<String>>,
+        env: Option<HashMap<String, String>>,
+        timeout: Option<u64>,
+    }
+
+    impl CommandConfigure {
+        /// Default is true
+        pub fn get_enabled(&self) -> bool {
+            self.enabled.unwrap_or(true)
+        }
+
+        pub fn get_program(&self) -> &str {
+            &self.program
+        }
+
+        /// Seconds before program is killed, default is 30
+        pub fn get_timeout(&self) -> Duration {
+            Duration::from_secs(self.timeout.unwrap_or(30))
+        }
+    }
+
+    /// Addresses in program output, separated by whitespace. Anything else is ignored.
+    pub(crate) fn parse_output(output: &str) -> Vec<IpAddr> {
+        output
+            .split_whitespace()
+            .filter_map(|word| word.parse().ok())
+            .collect()
+    }
+
+    /// Run an external program and read addresses from its stdout
+    pub struct CommandIPSource {
+        program: String,
+        args: Vec<String>,
+        env: HashMap<String, String>,
+        timeout: Duration,
+        ipv4: bool,
+        ipv6: bool,
+    }
+
+    impl CommandIPSource {
+        pub fn new(configure: &CommandConfigure, ipv4: bool, ipv6: bool) -> Self {
+            Self {
+                program: configure.program.clone(),
+                args: configure.args.clone().unwrap_or_default(),
+                env: configure.env.clone().unwrap_or_default(),
+                timeout: configure.get_timeout(),
+                ipv4,
+                ipv6,
+            }
+        }
+
+        async fn run(&self) -> anyhow::Result<String> {
+            let child = tokio::process::Command::new(&self.program)
+                .args(&self.args)
+                .envs(&self.env)
+                .stdin(std::process::Stdio::null())
+                // Program is killed if timeout future is dropped
+                .kill_on_drop(true)
+                .output();
+            let output = tokio::time::timeout(self.timeout, child)
+                .await
+                .map_err(|_| anyhow!("Command {} timeout after {:?}", self.program, self.timeout))?
+                .map_err(|e| anyhow!("Run command {} error: {e}", self.program))?;
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            if !output.status.success() {
+                return Err(anyhow!(
+                    "Command {} exited with {}: {}",
+                    self.program,
+                    output.status,
+                    stderr.trim()
+                ));
+            }
+            if !stderr.trim().is_empty() {
+                debug!("Command {} stderr: {}", self.program, stderr.trim());
+            }
+            Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl IPSource for CommandIPSource {
+        async fn get_current_ip(&self) -> anyhow::Result<CurrentIP> {
+            let output = self.run().await?;
+            let current = CurrentIP::first_of(parse_output(&output), self.ipv4, self.ipv6);
+            if current.is_empty() {
+                warn!("Output of {}: {output:?}", self.program);
+                return Err(anyhow!("No address in output of {}", self.program));
+            }
+            Ok(current)
+        }
+    }
+}
@@ -17,31 +17,73 @@
  ** You should have received a copy of the GNU Affero General Public License
  ** along with this program. If not, see <https://www.gnu.org/licenses/>.
  */
+/// Timeout of HTTP requests in seconds
+pub(crate) const DEFAULT_TIMEOUT: u64 = 10;
+
 pub(crate) mod parser {
+    use crate::address::api::{AddressFilter, FilteredIPSource};
+    use crate::backoff::Backoff;
     use crate::cloudflare_api::api::CloudFlareConfigure;
-    use crate::configparser::{IPSource, NameServer};
+    use crate::command::api::{CommandConfigure, CommandIPSource};
+    use crate::configparser::{CurrentIP, IPSource, NameServer, RecordType, DEFAULT_TIMEOUT};
     use crate::custom_target::api::{CustomUpstream, CustomUpstreamConfigure};
-    use crate::openwrt::api::OpenWRTConfigure;
+    use crate::dns::api::{DnsConfigure, DnsIPSource};
+    use crate::firewall::api::{FirewallConfigure, FirewallIPSource};
+    use crate::fritzbox::api::{FritzboxConfigure, FritzboxIPSource};
+    use crate::gateway::api::{GatewayConfigure, GatewayIPSource};
+    use crate::interface::api::{InterfaceConfigure, InterfaceIPSource};
+    use crate::mikrotik::api::{MikrotikConfigure, MikrotikIPSource};
+    use crate::openwrt::api::{OpenWRTBackend, OpenWRTConfigure};
+    use crate::stun::api::{StunConfigure, StunIPSource};
     use crate::{cloudflare_api, openwrt};
     use anyhow::anyhow;
-    use log::{error, info};
+    use ipnet::IpNet;
+    use log::{error, info, warn};
     use serde::Deserialize;
     use tap::TapFallible;
 
-    use std::path::Path;
+    use std::net::IpAddr;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    const DEFAULT_STATE_FILE: &str = "state.json";
 
     #[derive(Deserialize)]
     pub struct Configure {
         account: AccountConfigure,
         cloudflare: CloudFlareConfigure,
         openwrt: OpenWRTConfigure,
+        firewall: Option<FirewallConfigure>,
+        fritzbox: Option<FritzboxConfigure>,
+        interface: Option<InterfaceConfigure>,
+        stun: Option<StunConfigure>,
+        dns: Option<DnsConfigure>,
+        gateway: Option<GatewayConfigure>,
+        command: Option<CommandConfigure>,
+        mikrotik: Option<MikrotikConfigure>,
         custom_upstream: Option<CustomUpstreamConfigure>,
     }
 
     #[derive(Deserialize)]
     pub struct AccountConfigure {
         extern_ip_uris: Option<Vec<String>>,
+        extern_ipv6_uris: Option<Vec<String>>,
+        ipv4: Option<bool>,
+        ipv6: Option<bool>,
         duration: Option<i32>,
+        retry_initial: Option<u64>,
+        retry_max_delay: Option<u64>,
+        retry_multiplier: Option<f64>,
+        retry_jitter: Option<f64>,
+        give_up_after: Option<f64>,
+        give_up_exit_code: Option<i32>,
+        state_file: Option<PathBuf>,
+        state_in_memory: Option<bool>,
+        reconcile_interval: Option<u64>,
+        watch_interfaces: Option<Vec<String>>,
+        quorum: Option<usize>,
+        allow_ranges: Option<Vec<IpNet>>,
     }
 
     impl AccountConfigure {
@@ -49,12 +91,118 @@ pub(crate) mod parser {
             &self.extern_ip_uris
         }
 
+        fn get_extern_ipv6_uris(&self) -> &Option<Vec<String>> {
+            &self.extern_ipv6_uris
+        }
+
+        /// Default is true
+        pub fn get_ipv4(&self) -> bool {
+            self.ipv4.unwrap_or(true)
+        }
+
+        /// Default is false
+        pub fn get_ipv6(&self) -> bool {
+            self.ipv6.unwrap_or(false)
+        }
+
         fn get_duration(&self) -> u32 {
             self.duration.unwrap_or(600) as u32
         }
+
+        /// Default is `state.json` under `$STATE_DIRECTORY` or XDG state directory.
+        /// State is kept in memory only if `state_in_memory` is true or no state
+        /// directory is available.
+        pub fn get_state_file(&self) -> Option<PathBuf> {
+            if self.state_in_memory.unwrap_or(false) {
+                return None;
+            }
+            self.state_file.clone().or_else(|| {
+                openwrt::api::default_state_directory()
+                    .map(|directory| directory.join(DEFAULT_STATE_FILE))
+            })
+        }
+
+        /// Contact name server even if IP is not changed after this interval,
+        /// default is 86400 seconds
+        pub fn get_reconcile_interval(&self) -> u64 {
+            self.reconcile_interval.unwrap_or(86400)
+        }
+
+        /// Query every extern IP URI concurrently and require this many of them
+        /// to report the same address, first valid response is used if not set
+        pub fn get_quorum(&self) -> Option<usize> {
+            self.quorum.filter(|quorum| *quorum > 0)
+        }
+
+        /// Addresses in these ranges are published even if private or reserved
+        pub fn get_allow_ranges(&self) -> Vec<IpNet> {
+            self.allow_ranges.clone().unwrap_or_default()
+        }
+
+        /// Update immediately when address of these interfaces changed, Linux only
+        pub fn get_watch_interfaces(&self) -> anyhow::Result<Vec<String>> {
+            let interfaces = self.watch_interfaces.clone().unwrap_or_default();
+            if cfg!(not(target_os = "linux")) && !interfaces.is_empty() {
+                return Err(anyhow!("watch_interfaces is only supported on Linux"));
+            }
+            Ok(interfaces)
+        }
+
+        /// Retry after `retry_initial` seconds (default 5), multiply by `retry_multiplier`
+        /// (default 2) every attempt, up to `retry_max_delay` seconds (default 600).
+        /// Process exits with `give_up_exit_code` (default 75) if still failing after
+        /// `give_up_after` hours, never give up by default.
+        pub fn get_backoff(&self) -> anyhow::Result<Backoff> {
+            let default = Backoff::default();
+            let jitter = self.retry_jitter.unwrap_or(0.2);
+            if jitter.is_nan() {
+                return Err(anyhow!("retry_jitter must be a number"));
+            }
+            let (initial, max_delay) = (
+                self.retry_initial.unwrap_or(5),
+                self.retry_max_delay.unwrap_or(600),
+            );
+            if initial == 0 || max_delay == 0 {
+                return Err(anyhow!(
+                    "retry_initial and retry_max_delay must be at least 1 second"
+                ));
+            }
+            let give_up_after = self
+                .give_up_after
+                .map(|hours| Duration::try_from_secs_f64(hours * 3600.0))
+                .transpose()
+                .map_err(|_| anyhow!("give_up_after must be a non-negative number of hours"))?;
+            Ok(Backoff::new(
+                Duration::from_secs(initial),
+                Duration::from_secs(max_delay),
+                self.retry_multiplier.unwrap_or(2.0),
+                jitter,
+                give_up_after,
+                self.give_up_exit_code
+                    .unwrap_or(default.give_up_exit_code()),
+            ))
+        }
     }
 
     impl Configure {
+        /// AAAA records need IPv6 detection, enable it unless explicitly disabled
+        pub fn check_address_families(&mut self) -> anyhow::Result<()> {
+            if !self.cloudflare.get_enabled() || !self.cloudflare.manages(RecordType::AAAA) {
+                return Ok(());
+            }
+            match self.account.ipv6 {
+                Some(false) => Err(anyhow!(
+                    "AAAA records are managed but IPv6 is disabled by [account] ipv6 = false"
+                )),
+                Some(true) => Ok(()),
+                None => {
+                    info!("AAAA records are managed, enable IPv6 address detection");
+                    self.account.ipv6 = Some(true);
+                    Ok(())
+                }
+            }
+        }
+
         pub fn get_account(&self) -> &AccountConfigure {
             &self.account
         }
@@ -67,96 +215,727 @@ pub(crate) mod parser {
             &self.openwrt
         }
 
+        pub fn get_firewall_configure(&self) -> &Option<FirewallConfigure> {
+            &self.firewall
+        }
+
+        pub fn get_fritzbox_configure(&self) -> &Option<FritzboxConfigure> {
+            &self.fritzbox
+        }
+
+        pub fn get_interface_configure(&self) -> &Option<InterfaceConfigure> {
+            &self.interface
+        }
+
+        pub fn get_stun_configure(&self) -> &Option<StunConfigure> {
+            &self.stun
+        }
+
+        pub fn get_dns_configure(&self) -> &Option<DnsConfigure> {
+            &self.dns
+        }
+
+        pub fn get_gateway_configure(&self) -> &Option<GatewayConfigure> {
+            &self.gateway
+        }
+
+        pub fn get_command_configure(&self) -> &Option<CommandConfigure> {
+            &self.command
+        }
+
+        pub fn get_mikrotik_configure(&self) -> &Option<MikrotikConfigure> {
+            &self.mikrotik
+        }
+
         pub fn get_cloudflare_configure(&self) -> &CloudFlareConfigure {
             &self.cloudflare
         }
+
+        /// Sections of enabled IP sources, in order of precedence
+        pub fn get_enabled_ip_sources(&self) -> Vec<&'static str> {
+            [
+                ("openwrt", self.openwrt.get_status()),
+                (
+                    "firewall",
+                    self.firewall.as_ref().is_some_and(|c| c.get_enabled()),
+                ),
+                (
+                    "fritzbox",
+                    self.fritzbox.as_ref().is_some_and(|c| c.get_enabled()),
+                ),
+                (
+                    "mikrotik",
+                    self.mikrotik.as_ref().is_some_and(|c| c.get_enabled()),
+                ),
+                (
+                    "interface",
+                    self.interface.as_ref().is_some_and(|c| c.get_enabled()),
+                ),
+                ("stun", self.stun.as_ref().is_some_and(|c| c.get_enabled())),
+                ("dns", self.dns.as_ref().is_some_and(|c| c.get_enabled())),
+                (
+                    "gateway",
+                    self.gateway.as_ref().is_some_and(|c| c.get_enabled()),
+                ),
+                (
+                    "command",
+                    self.command.as_ref().is_some_and(|c| c.get_enabled()),
+                ),
+            ]
+            .into_iter()
+            .filter_map(|(name, enabled)| enabled.then_some(name))
+            .collect()
+        }
+    }
+
+    pub struct ConfigureValue {
+        pub name_servers: Vec<Arc<dyn NameServer>>,
+        pub ip_source: Box<dyn IPSource>,
+        pub duration: u32,
+        pub backoff: Backoff,
+        pub state_file: Option<PathBuf>,
+        pub reconcile_interval: u64,
+        pub watch_interfaces: Vec<String>,
     }
 
     pub async fn get_configure_value<P: AsRef<Path> + std::fmt::Debug>(
         configure_path: P,
-    ) -> anyhow::Result<(Box<dyn NameServer>, Box<dyn IPSource>, u32)> {
+    ) -> anyhow::Result<ConfigureValue> {
         let contents = tokio::fs::read_to_string(configure_path).await?;
-        let configure: Configure =
+        let mut configure: Configure =
             toml::from_str(&contents).tap_err(|e| error!("Read configure file error: {e:?}"))?;
+        configure.check_address_families()?;
 
         let openwrt_config = configure.get_openwrt_configure();
         let ip_source_client: Box<dyn IPSource> = if openwrt_config.get_status() {
-            Box::new(openwrt::api::Client::new(
-                openwrt_config.get_user().as_ref().cloned().unwrap(),
-                openwrt_config.get_password().as_ref().cloned().unwrap(),
-                openwrt_config.get_route().as_ref().cloned().unwrap(),
+            let (ipv4, ipv6) = (
+                configure.get_account().get_ipv4(),
+                configure.get_account().get_ipv6(),
+            );
+            match openwrt_config.get_backend() {
+                OpenWRTBackend::Luci => Box::new(
+                    openwrt::api::Client::new(openwrt_config, ipv4, ipv6)
+                        .tap_err(|e| error!("OpenWRT configure error: {e:?}"))?,
+                ),
+                OpenWRTBackend::Ubus => {
+                    info!("Read IP address from OpenWRT ubus");
+                    Box::new(
+                        openwrt::api::UbusClient::new(openwrt_config, ipv4, ipv6)
+                            .tap_err(|e| error!("OpenWRT configure error: {e:?}"))?,
+                    )
+                }
+            }
+        } else if let Some(firewall) = configure
+            .get_firewall_configure()
+            .as_ref()
+            .filter(|firewall| firewall.get_enabled())
+        {
+            info!(
+                "Read IP address of interface {} from {:?}",
+                firewall.get_interface(),
+                firewall.get_kind()
+            );
+            Box::new(
+                FirewallIPSource::new(
+                    firewall,
+                    configure.get_account().get_ipv4(),
+                    configure.get_account().get_ipv6(),
+                )
+                .tap_err(|e| error!("Firewall configure error: {e:?}"))?,
+            )
+        } else if let Some(fritzbox) = configure
+            .get_fritzbox_configure()
+            .as_ref()
+            .filter(|fritzbox| fritzbox.get_enabled())
+        {
+            info!("Read IP address from FRITZ!Box {}", fritzbox.get_route());
+            Box::new(
+                FritzboxIPSource::new(
+                    fritzbox,
+                    configure.get_account().get_ipv4(),
+                    configure.get_account().get_ipv6(),
+                )
+                .tap_err(|e| error!("FRITZ!Box configure error: {e:?}"))?,
+            )
+        } else if let Some(mikrotik) = configure
+            .get_mikrotik_configure()
+            .as_ref()
+            .filter(|mikrotik| mikrotik.get_enabled())
+        {
+            info!("Read IP address from RouterOS {:?} API", mikrotik.get_api());
+            Box::new(
+                MikrotikIPSource::new(
+                    mikrotik,
+                    configure.get_account().get_ipv4(),
+                    configure.get_account().get_ipv6(),
+                )
+                .tap_err(|e| error!("RouterOS configure error: {e:?}"))?,
+            )
+        } else if let Some(interface) = configure
+            .get_interface_configure()
+            .as_ref()
+            .filter(|interface| interface.get_enabled())
+        {
+            info!("Read IP address from interface {}", interface.get_name());
+            Box::new(
+                InterfaceIPSource::new(
+                    interface,
+                    configure.get_account().get_ipv4(),
+                    configure.get_account().get_ipv6(),
+                )
+                .tap_err(|e| error!("Interface configure error: {e:?}"))?,
+            )
+        } else if let Some(quorum) = configure.get_account().get_quorum() {
+            let (ipv4, ipv6) = (
+                configure.get_account().get_ipv4(),
+                configure.get_account().get_ipv6(),
+            );
+            let mut sources = DefaultIPSource::per_uri(configure.get_account());
+            if let Some(stun) = configure
+                .get_stun_configure()
+                .as_ref()
+                .filter(|stun| stun.get_enabled())
+            {
+                sources.push(("stun".into(), Arc::new(StunIPSource::new(stun, ipv4, ipv6))));
+            }
+            if let Some(dns) = configure
+                .get_dns_configure()
+                .as_ref()
+                .filter(|dns| dns.get_enabled())
+            {
+                sources.push(("dns".into(), Arc::new(DnsIPSource::new(dns, ipv4, ipv6))));
+            }
+            info!(
+                "Discover IP address by quorum {quorum} of {}",
+                sources
+                    .iter()
+                    .map(|(name, _)| name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            Box::new(QuorumIPSource::new(sources, quorum, ipv4, ipv6))
+        } else if let Some(stun) = configure
+            .get_stun_configure()
+            .as_ref()
+            .filter(|stun| stun.get_enabled())
+        {
+            info!("Discover IP address by STUN");
+            Box::new(StunIPSource::new(
+                stun,
+                configure.get_account().get_ipv4(),
+                configure.get_account().get_ipv6(),
             ))
-        } else {
-            Box::new(DefaultIPSource::new(
-                configure.get_account().get_extern_ip_uris(),
+        } else if let Some(dns) = configure
+            .get_dns_configure()
+            .as_ref()
+            .filter(|dns| dns.get_enabled())
+        {
+            info!("Discover IP address by DNS query");
+            Box::new(DnsIPSource::new(
+                dns,
+                configure.get_account().get_ipv4(),
+                configure.get_account().get_ipv6(),
+            ))
+        } else if let Some(gateway) = configure
+            .get_gateway_configure()
+            .as_ref()
+            .filter(|gateway| gateway.get_enabled())
+        {
+            info!(
+                "Ask gateway for IP address by {:?}",
+                gateway.get_protocols()
+            );
+            Box::new(GatewayIPSource::new(
+                gateway,
+                configure.get_account().get_ipv4(),
+                configure.get_account().get_ipv6(),
+            ))
+        } else if let Some(command) = configure
+            .get_command_configure()
+            .as_ref()
+            .filter(|command| command.get_enabled())
+        {
+            info!("Read IP address from output of {}", command.get_program());
+            Box::new(CommandIPSource::new(
+                command,
+                configure.get_account().get_ipv4(),
+                configure.get_account().get_ipv6(),
             ))
+        } else {
+            Box::new(DefaultIPSource::new(configure.get_account()))
+        };
+        // Only the first enabled source is used, unless [stun] and [dns] join the quorum
+        let enabled = configure.get_enabled_ip_sources();
+        let used = match enabled.first() {
+            Some(&("stun" | "dns" | "gateway" | "command"))
+                if configure.get_account().get_quorum().is_some() =>
+            {
+                vec!["stun", "dns"]
+            }
+            Some(&first) => vec![first],
+            None => vec![],
         };
+        for ignored in enabled.iter().filter(|name| !used.contains(name)) {
+            warn!("[{ignored}] is enabled but ignored, only one IP source is used unless combined by quorum");
+        }
 
+        let mut name_servers: Vec<Arc<dyn NameServer>> = Vec::new();
         let cf_configure = configure.get_cloudflare_configure();
-        let ns: Box<dyn NameServer> = if cf_configure.get_enabled() {
-            Box::new(cloudflare_api::api::Configure::new(
+        if cf_configure.get_enabled() {
+            let cf = cloudflare_api::api::Configure::new(
                 cf_configure.get_domain().clone().unwrap(),
-                cf_configure.get_token().as_ref().unwrap(),
-            ))
-        } else {
-            info!("Use custom upstream instead of cloudflare");
-            Box::new(CustomUpstream::option_new(&configure).unwrap())
-        };
+                cf_configure.get_credentials(),
+                cf_configure.get_record_defaults(),
+            )
+            .tap_err(|e| error!("Cloudflare configure error: {e:?}"))?;
+            name_servers.push(Arc::new(cf));
+        }
+        if let Some(custom_upstream) = CustomUpstream::option_new(&configure) {
+            info!("Custom upstream enabled");
+            name_servers.push(Arc::new(custom_upstream));
+        }
+        if name_servers.is_empty() {
+            return Err(anyhow!("No name server is enabled"));
+        }
 
-        Ok((ns, ip_source_client, configure.get_account().get_duration()))
+        Ok(ConfigureValue {
+            name_servers,
+            ip_source: Box::new(FilteredIPSource::new(
+                ip_source_client,
+                AddressFilter::new(configure.get_account().get_allow_ranges()),
+            )),
+            duration: configure.get_account().get_duration(),
+            backoff: configure
+                .get_account()
+                .get_backoff()
+                .tap_err(|e| error!("Backoff configure error: {e}"))?,
+            state_file: configure.get_account().get_state_file(),
+            reconcile_interval: configure.get_account().get_reconcile_interval(),
+            watch_interfaces: configure
+                .get_account()
+                .get_watch_interfaces()
+                .tap_err(|e| error!("Watch interfaces configure error: {e}"))?,
+        })
     }
     // TODO: ADD CUSTOM EXTERN IP URI
 
     pub struct DefaultIPSource {
         uris: Vec<String>,
+        ipv6_uris: Vec<String>,
+        session: reqwest::Client,
     }
 
     impl DefaultIPSource {
-        fn new(extern_uris: &Option<Vec<String>>) -> DefaultIPSource {
-            Self {
-                uris: match extern_uris {
+        pub(crate) fn new(account: &AccountConfigure) -> DefaultIPSource {
+            let select = |enabled: bool, uris: &Option<Vec<String>>, default: &str| {
+                if !enabled {
+                    return vec![];
+                }
+                match uris {
                     Some(uris) => uris.clone(),
-                    None => vec!["https://api-ipv4.ip.sb/ip".into()],
-                },
+                    None => vec![default.into()],
+                }
+            };
+            Self::with_uris(
+                select(
+                    account.get_ipv4(),
+                    account.get_extern_ip_uris(),
+                    "https://api-ipv4.ip.sb/ip",
+                ),
+                select(
+                    account.get_ipv6(),
+                    account.get_extern_ipv6_uris(),
+                    "https://api-ipv6.ip.sb/ip",
+                ),
+            )
+        }
+
+        fn with_uris(uris: Vec<String>, ipv6_uris: Vec<String>) -> DefaultIPSource {
+            Self {
+                uris,
+                ipv6_uris,
+                session: reqwest::Client::builder()
+                    .timeout(Duration::from_secs(DEFAULT_TIMEOUT))
+                    .connect_timeout(Duration::from_secs(DEFAULT_TIMEOUT))
+                    .build()
+                    .unwrap(),
+            }
+        }
+
+        /// One source per extern IP URI, so each of them votes separately in a quorum
+        pub(crate) fn per_uri(account: &AccountConfigure) -> Vec<(String, Arc<dyn IPSource>)> {
+            let source = Self::new(account);
+            let v4 = source
+                .uris
+                .iter()
+                .map(|uri| (uri, Self::with_uris(vec![uri.clone()], vec![])));
+            let v6 = source
+                .ipv6_uris
+                .iter()
+                .map(|uri| (uri, Self::with_uris(vec![], vec![uri.clone()])));
+            v4.chain(v6)
+                .map(|(uri, source)| (uri.clone(), Arc::new(source) as Arc<dyn IPSource>))
+                .collect()
+        }
+
+        /// Fetch address from single uri, response body must be an address of expected family
+        async fn fetch_ip_from_uri(
+            session: &reqwest::Client,
+            uri: &str,
+            ipv6: bool,
+        ) -> anyhow::Result<IpAddr> {
+            let text = session
+                .get(uri)
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await
+                .map_err(|e| anyhow!("Fetch text error: {e:?}"))?;
+            let address = text
+                .trim()
+                .parse::<IpAddr>()
+                .map_err(|_| anyhow!("Response of {uri} is not an IP address"))?;
+            if address.is_ipv6() != ipv6 {
+                return Err(anyhow!(
+                    "Response of {uri} is not an IPv{} address",
+                    if ipv6 { 6 } else { 4 }
+                ));
             }
+            Ok(address)
         }
 
-        async fn fetch_ip_from_extern_uris(uris: &[String]) -> anyhow::Result<String> {
+        async fn fetch_ip_from_extern_uris(
+            &self,
+            uris: &[String],
+            ipv6: bool,
+        ) -> anyhow::Result<IpAddr> {
             assert!(!uris.is_empty(), "Uris should not empty");
 
-            for i in 0..uris.len() {
-                let result = reqwest::get(&uris[i]).await;
-                match result {
-                    Ok(resp) => {
-                        return Ok(resp
-                            .text()
-                            .await
-                            .map_err(|e| anyhow!("Fetch text error: {e:?}"))?
-                            .trim()
-                            .into());
-                    }
+            let mut last_error = None;
+            for uri in uris {
+                match Self::fetch_ip_from_uri(&self.session, uri, ipv6).await {
+                    Ok(address) => return Ok(address),
                     Err(e) => {
-                        if i == uris.len() - 1 {
-                            return Err(e.into());
-                        }
+                        warn!("Fetch address from {uri} error: {e:?}");
+                        last_error = Some(e);
                     }
                 }
             }
-            unreachable!()
+            Err(last_error.unwrap())
+        }
+    }
+
+    /// Pick the address reported by most sources, at least `quorum` of them must agree
+    pub(crate) fn select_by_quorum(
+        responses: &[(String, IpAddr)],
+        quorum: usize,
+    ) -> anyhow::Result<IpAddr> {
+        let mut votes: Vec<(IpAddr, Vec<&str>)> = Vec::new();
+        for (uri, address) in responses {
+            match votes.iter_mut().find(|(voted, _)| voted == address) {
+                Some((_, uris)) => uris.push(uri),
+                None => votes.push((*address, vec![uri])),
+            }
+        }
+        votes.sort_by_key(|(_, uris)| std::cmp::Reverse(uris.len()));
+        if votes.len() > 1 {
+            warn!(
+                "Extern IP sources disagree: {}",
+                votes
+                    .iter()
+                    .map(|(address, uris)| format!("{address} ({})", uris.join(", ")))
+                    .collect::<Vec<_>>()
+                    .join("; ")
+            );
+        }
+        match votes.first() {
+            Some((address, uris)) if uris.len() >= quorum => Ok(*address),
+            Some((address, uris)) => Err(anyhow!(
+                "Quorum not reached, best answer {address} from {} of {quorum} required source(s)",
+                uris.len()
+            )),
+            None => Err(anyhow!("No extern IP source answered")),
         }
     }
 
     #[async_trait::async_trait]
     impl IPSource for DefaultIPSource {
-        async fn get_current_ip(&self) -> anyhow::Result<String> {
-            Self::fetch_ip_from_extern_uris(&self.uris).await
+        async fn get_current_ip(&self) -> anyhow::Result<CurrentIP> {
+            let mut current = CurrentIP::default();
+            if !self.uris.is_empty() {
+                current.v4 = self
+                    .fetch_ip_from_extern_uris(&self.uris, false)
+                    .await
+                    .tap_err(|e| warn!("Fetch IPv4 address error: {e:?}"))
+                    .ok()
+                    .and_then(|address| match address {
+                        IpAddr::V4(v4) => Some(v4),
+                        IpAddr::V6(_) => None,
+                    });
+            }
+            if !self.ipv6_uris.is_empty() {
+                current.v6 = self
+                    .fetch_ip_from_extern_uris(&self.ipv6_uris, true)
+                    .await
+                    .tap_err(|e| warn!("Fetch IPv6 address error: {e:?}"))
+                    .ok()
+                    .and_then(|address| match address {
+                        IpAddr::V4(_) => None,
+                        IpAddr::V6(v6) => Some(v6),
+                    });
+            }
+            if current.is_empty() {
+                return Err(anyhow!("Unable to fetch any IP address"));
+            }
+            Ok(current)
+        }
+    }
+
+    /// Query every source concurrently, accept an address once `quorum` of them agree
+    pub(crate) struct QuorumIPSource {
+        sources: Vec<(String, Arc<dyn IPSource>)>,
+        quorum: usize,
+        ipv4: bool,
+        ipv6: bool,
+    }
+
+    impl QuorumIPSource {
+        pub(crate) fn new(
+            sources: Vec<(String, Arc<dyn IPSource>)>,
+            quorum: usize,
+            ipv4: bool,
+            ipv6: bool,
+        ) -> QuorumIPSource {
+            if sources.len() < quorum {
+                warn!(
+                    "Quorum {quorum} can never be reached by {} IP source(s)",
+                    sources.len()
+                );
+            }
+            Self {
+                sources,
+                quorum,
+                ipv4,
+                ipv6,
+            }
+        }
+
+        fn reached(responses: &[(String, IpAddr)], quorum: usize) -> bool {
+            responses.iter().any(|(_, address)| {
+                responses
+                    .iter()
+                    .filter(|(_, voted)| voted == address)
+                    .count()
+                    >= quorum
+            })
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl IPSource for QuorumIPSource {
+        async fn get_current_ip(&self) -> anyhow::Result<CurrentIP> {
+            let mut tasks = tokio::task::JoinSet::new();
+            for (name, source) in self.sources.iter().cloned() {
+                tasks.spawn(async move { (name, source.get_current_ip().await) });
+            }
+            let (mut v4, mut v6) = (Vec::new(), Vec::new());
+            while let Some(joined) = tasks.join_next().await {
+                let (name, result) = joined?;
+                match result {
+                    Ok(current) => {
+                        if let Some(address) = current.v4 {
+                            v4.push((name.clone(), address.into()));
+                        }
+                        if let Some(address) = current.v6 {
+                            v6.push((name, address.into()));
+                        }
+                    }
+                    Err(e) => warn!("Fetch address from {name} error: {e:?}"),
+                }
+                // Remaining sources are aborted once the set is dropped
+                if (!self.ipv4 || Self::reached(&v4, self.quorum))
+                    && (!self.ipv6 || Self::reached(&v6, self.quorum))
+                {
+                    break;
+                }
+            }
+            let mut current = CurrentIP::default();
+            for (enabled, responses, family) in [(self.ipv4, &v4, 4), (self.ipv6, &v6, 6)] {
+                if enabled {
+                    match select_by_quorum(responses, self.quorum) {
+                        Ok(address) => current.set(address),
+                        Err(e) => warn!("Fetch IPv{family} address error: {e:?}"),
+                    }
+                }
+            }
+            if current.is_empty() {
+                return Err(anyhow!("Unable to fetch any IP address"));
+            }
+            Ok(current)
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RecordType {
+    A,
+    AAAA,
+}
+
+impl std::fmt::Display for RecordType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecordType::A => write!(f, "A"),
+            RecordType::AAAA => write!(f, "AAAA"),
+        }
+    }
+}
+
+impl std::str::FromStr for RecordType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "A" => Ok(RecordType::A),
+            "AAAA" => Ok(RecordType::AAAA),
+            _ => Err(anyhow::anyhow!("Unsupported record type {s}")),
         }
     }
 }
 
+/// Addresses reported by an [`IPSource`], one per address family.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct CurrentIP {
+    pub v4: Option<std::net::Ipv4Addr>,
+    pub v6: Option<std::net::Ipv6Addr>,
+    /// Addresses of each WAN interface, for sources aware of multiple uplinks
+    #[serde(default, skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    pub interfaces: std::collections::BTreeMap<String, CurrentIP>,
+}
+
+impl CurrentIP {
+    pub fn get(&self, record_type: RecordType) -> Option<std::net::IpAddr> {
+        match record_type {
+            RecordType::A => self.v4.map(Into::into),
+            RecordType::AAAA => self.v6.map(Into::into),
+        }
+    }
+
+    /// Address of `interface` if set, otherwise the default address
+    pub fn get_on(
+        &self,
+        interface: Option<&str>,
+        record_type: RecordType,
+    ) -> Option<std::net::IpAddr> {
+        match interface {
+            Some(interface) => self.interfaces.get(interface)?.get(record_type),
+            None => self.get(record_type),
+        }
+    }
+
+    /// Set address of the family `address` belongs to
+    pub fn set(&mut self, address: std::net::IpAddr) {
+        match address {
+            std::net::IpAddr::V4(v4) => self.v4 = Some(v4),
+            std::net::IpAddr::V6(v6) => self.v6 = Some(v6),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.v4.is_none() && self.v6.is_none()
+    }
+
+    /// First address of each enabled family in `addresses`
+    pub fn first_of(
+        addresses: impl IntoIterator<Item = std::net::IpAddr>,
+        ipv4: bool,
+        ipv6: bool,
+    ) -> CurrentIP {
+        let mut current = CurrentIP::default();
+        for address in addresses {
+            match address {
+                std::net::IpAddr::V4(v4) if ipv4 && current.v4.is_none() => current.v4 = Some(v4),
+                std::net::IpAddr::V6(v6) if ipv6 && current.v6.is_none() => current.v6 = Some(v6),
+                _ => {}
+            }
+        }
+        current
+    }
+}
+
+impl std::fmt::Display for CurrentIP {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let addresses = [self.get(RecordType::A), self.get(RecordType::AAAA)]
+            .into_iter()
+            .flatten()
+            .map(|address| address.to_string())
+            .collect::<Vec<_>>();
+        write!(f, "{}", addresses.join(", "))
+    }
+}
+
+/// Record changed by a [`NameServer`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UpdatedRecord {
+    pub name: String,
+    pub record_type: RecordType,
+    pub content: String,
+    /// Record id on provider, if provider has one
+    pub id: Option<String>,
+}
+
+impl UpdatedRecord {
+    pub fn new(name: &str, record_type: RecordType, content: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            record_type,
+            content: content.to_string(),
+            id: None,
+        }
+    }
+
+    pub fn with_id(mut self, id: &str) -> Self {
+        self.id = Some(id.to_string());
+        self
+    }
+}
+
+impl std::fmt::Display for UpdatedRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} -> {}", self.record_type, self.name, self.content)
+    }
+}
+
+#[derive(Debug)]
+pub enum UpdateOutcome {
+    /// Every record already points to current address
+    Unchanged,
+    Updated(Vec<UpdatedRecord>),
+    /// Records in `failed` could not be updated, the others in `updated` are changed
+    PartialFailure {
+        updated: Vec<UpdatedRecord>,
+        failed: Vec<(String, crate::error::Error)>,
+    },
+}
+
 #[async_trait::async_trait]
-pub trait NameServer {
-    async fn update_dns_result(&self, new_record: &str) -> Result<bool, reqwest::Error>;
+pub trait NameServer: Send + Sync {
+    /// Name used in log
+    fn name(&self) -> &str;
+
+    /// Description of desired records, published state is dropped once it changed
+    fn fingerprint(&self) -> String {
+        String::new()
+    }
+
+    /// `known` are records published before, name server may update them without querying
+    async fn update_dns_result(
+        &self,
+        new_record: &CurrentIP,
+        known: &[UpdatedRecord],
+    ) -> crate::error::Result<UpdateOutcome>;
 }
 
 /* #[async_trait::async_trait]
@@ -164,12 +943,12 @@ impl<F: ?Sized> NameServer for Box<F>
 where
     F: NameServer,
 {
-    async fn update_dns_result(&self, new_record: &str) -> Result<bool, reqwest::Error> {
+    async fn update_dns_result(&self, new_record: &CurrentIP) -> crate::error::Result<UpdateOutcome> {
         (**self).update_dns_result(new_record)
     }
 } */
 
 #[async_trait::async_trait]
-pub trait IPSource {
-    async fn get_current_ip(&self) -> anyhow::Result<String>;
+pub trait IPSource: Send + Sync {
+    async fn get_current_ip(&self) -> anyhow::Result<CurrentIP>;
 }
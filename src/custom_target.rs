@@ -18,19 +18,26 @@
  ** along with this program. If not, see <https://www.gnu.org/licenses/>.
  */
 pub(crate) mod api {
-    use crate::configparser::NameServer;
+    use crate::configparser::DEFAULT_TIMEOUT;
+    use crate::configparser::{CurrentIP, NameServer, RecordType, UpdateOutcome, UpdatedRecord};
+    use anyhow::anyhow;
 
     use serde::{Deserialize, Serialize};
+    use std::time::Duration;
 
     #[derive(Deserialize)]
     pub struct CustomUpstreamConfigure {
-        #[allow(dead_code)]
         enabled: Option<bool>,
         upstream_url: String,
         token: Option<String>,
     }
 
     impl CustomUpstreamConfigure {
+        /// Default is true
+        pub fn get_enabled(&self) -> bool {
+            self.enabled.unwrap_or(true)
+        }
+
         pub fn get_upstream(&self) -> &String {
             &self.upstream_url
         }
@@ -43,18 +50,31 @@ pub(crate) mod api {
     pub struct CustomUpstream {
         upstream_url: String,
         token: String,
+        session: reqwest::Client,
     }
 
     #[derive(Debug, Serialize, Deserialize)]
     pub struct PostBody {
-        data: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        data: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        data_v6: Option<String>,
         token: String,
     }
 
     impl PostBody {
         pub fn new(s: &str, token: &str) -> Self {
             Self {
-                data: s.to_string(),
+                data: Some(s.to_string()),
+                data_v6: None,
+                token: token.to_string(),
+            }
+        }
+
+        pub fn from_current_ip(current_ip: &CurrentIP, token: &str) -> Self {
+            Self {
+                data: current_ip.v4.map(|v4| v4.to_string()),
+                data_v6: current_ip.v6.map(|v6| v6.to_string()),
                 token: token.to_string(),
             }
         }
@@ -80,29 +100,68 @@ pub(crate) mod api {
     impl CustomUpstream {
         pub fn option_new(config: &crate::configparser::parser::Configure) -> Option<Self> {
             let upstream = config.get_custom_upstream();
-            upstream.as_ref().map(|source| Self {
-                upstream_url: source.get_upstream().clone(),
-                token: source.get_token().clone().unwrap_or_default(),
-            })
+            upstream
+                .as_ref()
+                .filter(|source| source.get_enabled())
+                .map(|source| Self {
+                    upstream_url: source.get_upstream().clone(),
+                    token: source.get_token().clone().unwrap_or_default(),
+                    session: reqwest::Client::builder()
+                        .timeout(Duration::from_secs(DEFAULT_TIMEOUT))
+                        .connect_timeout(Duration::from_secs(DEFAULT_TIMEOUT))
+                        .build()
+                        .unwrap(),
+                })
         }
 
-        pub fn to_post_body(&self, s: &str) -> PostBody {
-            PostBody::new(s, self.token.as_str())
+        pub fn to_post_body(&self, current_ip: &CurrentIP) -> PostBody {
+            PostBody::from_current_ip(current_ip, self.token.as_str())
         }
     }
 
     #[async_trait::async_trait]
     impl NameServer for CustomUpstream {
-        async fn update_dns_result(&self, new_record: &str) -> Result<bool, reqwest::Error> {
-            let response: PostResponse = reqwest::ClientBuilder::new()
-                .build()?
+        fn name(&self) -> &str {
+            "custom_upstream"
+        }
+
+        fn fingerprint(&self) -> String {
+            self.upstream_url.clone()
+        }
+
+        async fn update_dns_result(
+            &self,
+            new_record: &CurrentIP,
+            _known: &[UpdatedRecord],
+        ) -> crate::error::Result<UpdateOutcome> {
+            if new_record.v4.is_none() && new_record.v6.is_none() {
+                return Err(anyhow!("No address to publish to custom upstream").into());
+            }
+            let response: PostResponse = self
+                .session
                 .post(&self.upstream_url)
                 .json(&self.to_post_body(new_record))
                 .send()
                 .await?
                 .json()
                 .await?;
-            Ok(response.get_status() == 200)
+            if response.get_status() != 200 {
+                return Err(anyhow!(
+                    "Custom upstream rejected update with status {}",
+                    response.get_status()
+                )
+                .into());
+            }
+            Ok(UpdateOutcome::Updated(
+                [RecordType::A, RecordType::AAAA]
+                    .into_iter()
+                    .filter_map(|t| {
+                        new_record
+                            .get(t)
+                            .map(|content| UpdatedRecord::new(self.name(), t, &content.to_string()))
+                    })
+                    .collect(),
+            ))
         }
     }
 }
@@ -0,0 +1,327 @@
+/*
+ ** Copyright (C) 2021-2024 KunoiSayami
+ **
+ ** This file is part of passive-DDNS and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+pub(crate) mod api {
+    use crate::configparser::{CurrentIP, IPSource};
+    use crate::udp::exchange;
+    use anyhow::anyhow;
+    use log::{debug, warn};
+    use rand::Rng;
+    use serde::Deserialize;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+    use std::time::Duration;
+
+    #[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum QueryType {
+        A,
+        #[serde(rename = "AAAA")]
+        Aaaa,
+        #[serde(rename = "TXT")]
+        Txt,
+    }
+
+    impl QueryType {
+        fn code(&self) -> u16 {
+            match self {
+                QueryType::A => 1,
+                QueryType::Aaaa => 28,
+                QueryType::Txt => 16,
+            }
+        }
+    }
+
+    #[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub enum QueryClass {
+        #[default]
+        #[serde(rename = "IN")]
+        In,
+        /// CHAOS class, used by `whoami.cloudflare`
+        #[serde(rename = "CH")]
+        Ch,
+    }
+
+    impl QueryClass {
+        fn code(&self) -> u16 {
+            match self {
+                QueryClass::In => 1,
+                QueryClass::Ch => 3,
+            }
+        }
+    }
+
+    #[derive(Deserialize, Clone, Debug)]
+    pub struct DnsQuery {
+        name: String,
+        #[serde(rename = "type")]
+        query_type: QueryType,
+        #[serde(default)]
+        class: QueryClass,
+        /// Nameserver address, port is 53 if omitted
+        server: String,
+    }
+
+    impl DnsQuery {
+        fn new(name: &str, query_type: QueryType, class: QueryClass, server: &str) -> Self {
+            Self {
+                name: name.to_string(),
+                query_type,
+                class,
+                server: server.to_string(),
+            }
+        }
+
+        fn server_address(&self) -> anyhow::Result<SocketAddr> {
+            self.server
+                .parse::<SocketAddr>()
+                .or_else(|_| {
+                    self.server
+                        .parse::<IpAddr>()
+                        .map(|ip| SocketAddr::new(ip, 53))
+                })
+                .map_err(|_| anyhow!("Invalid nameserver address {}", self.server))
+        }
+
+        /// Whether answer of this query is an IPv6 address
+        fn is_ipv6(&self) -> anyhow::Result<bool> {
+            Ok(match self.query_type {
+                QueryType::A => false,
+                QueryType::Aaaa => true,
+                // Resolver reports address of the source, which has the same family as server
+                QueryType::Txt => self.server_address()?.is_ipv6(),
+            })
+        }
+    }
+
+    #[derive(Deserialize)]
+    pub struct DnsConfigure {
+        enabled: Option<bool>,
+        queries: Option<Vec<DnsQuery>>,
+        timeout: Option<u64>,
+    }
+
+    impl DnsConfigure {
+        /// Default is true
+        pub fn get_enabled(&self) -> bool {
+            self.enabled.unwrap_or(true)
+        }
+
+        pub fn get_queries(&self) -> Vec<DnsQuery> {
+            self.queries.clone().unwrap_or_else(|| {
+                vec![
+                    DnsQuery::new(
+                        "myip.opendns.com",
+                        QueryType::A,
+                        QueryClass::In,
+                        "208.67.222.222",
+                    ),
+                    DnsQuery::new(
+                        "whoami.cloudflare",
+                        QueryType::Txt,
+                        QueryClass::Ch,
+                        "1.1.1.1",
+                    ),
+                    DnsQuery::new(
+                        "myip.opendns.com",
+                        QueryType::Aaaa,
+                        QueryClass::In,
+                        "2620:119:35::35",
+                    ),
+                    DnsQuery::new(
+                        "whoami.cloudflare",
+                        QueryType::Txt,
+                        QueryClass::Ch,
+                        "2606:4700:4700::1111",
+                    ),
+                ]
+            })
+        }
+
+        /// Timeout of each request in seconds, default is 3
+        pub fn get_timeout(&self) -> Duration {
+            Duration::from_secs(self.timeout.unwrap_or(3))
+        }
+    }
+
+    pub(crate) fn build_query(
+        id: u16,
+        name: &str,
+        query_type: QueryType,
+        class: QueryClass,
+    ) -> Vec<u8> {
+        let mut message = Vec::new();
+        message.extend_from_slice(&id.to_be_bytes());
+        // Recursion desired
+        message.extend_from_slice(&0x0100u16.to_be_bytes());
+        // QDCOUNT = 1, ANCOUNT = NSCOUNT = ARCOUNT = 0
+        message.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+        for label in name.trim_end_matches('.').split('.') {
+            message.push(label.len() as u8);
+            message.extend_from_slice(label.as_bytes());
+        }
+        message.push(0);
+        message.extend_from_slice(&query_type.code().to_be_bytes());
+        message.extend_from_slice(&class.code().to_be_bytes());
+        message
+    }
+
+    /// Skip a (possibly compressed) domain name, return offset after it
+    fn skip_name(message: &[u8], mut offset: usize) -> Option<usize> {
+        loop {
+            let length = *message.get(offset)? as usize;
+            match length {
+                0 => return Some(offset + 1),
+                // Compression pointer takes 2 bytes and ends the name
+                l if l & 0xc0 == 0xc0 => return Some(offset + 2),
+                l => offset += 1 + l,
+            }
+        }
+    }
+
+    fn read_u16(message: &[u8], offset: usize) -> Option<u16> {
+        Some(u16::from_be_bytes(
+            message.get(offset..offset + 2)?.try_into().ok()?,
+        ))
+    }
+
+    /// Parse addresses in answer section of response
+    pub(crate) fn parse_response(
+        message: &[u8],
+        id: u16,
+        query_type: QueryType,
+    ) -> anyhow::Result<Vec<IpAddr>> {
+        let malformed = || anyhow!("Malformed DNS response");
+        if read_u16(message, 0).ok_or_else(malformed)? != id {
+            return Err(anyhow!("DNS response id mismatch"));
+        }
+        let flags = read_u16(message, 2).ok_or_else(malformed)?;
+        if flags & 0x000f != 0 {
+            return Err(anyhow!("DNS response error, rcode {}", flags & 0x000f));
+        }
+        let question_count = read_u16(message, 4).ok_or_else(malformed)?;
+        let answer_count = read_u16(message, 6).ok_or_else(malformed)?;
+
+        let mut offset = 12;
+        for _ in 0..question_count {
+            offset = skip_name(message, offset).ok_or_else(malformed)? + 4;
+        }
+        let mut addresses = Vec::new();
+        for _ in 0..answer_count {
+            offset = skip_name(message, offset).ok_or_else(malformed)?;
+            let record_type = read_u16(message, offset).ok_or_else(malformed)?;
+            let length = read_u16(message, offset + 8).ok_or_else(malformed)? as usize;
+            offset += 10;
+            let data = message.get(offset..offset + length).ok_or_else(malformed)?;
+            offset += length;
+            if record_type != query_type.code() {
+                continue;
+            }
+            match query_type {
+                QueryType::A => {
+                    let octets: [u8; 4] = data.try_into().map_err(|_| malformed())?;
+                    addresses.push(IpAddr::V4(Ipv4Addr::from(octets)));
+                }
+                QueryType::Aaaa => {
+                    let octets: [u8; 16] = data.try_into().map_err(|_| malformed())?;
+                    addresses.push(IpAddr::V6(Ipv6Addr::from(octets)));
+                }
+                QueryType::Txt => {
+                    // TXT data is a sequence of <length><string>
+                    let mut text = String::new();
+                    let mut rest = data;
+                    while let Some((&length, remain)) = rest.split_first() {
+                        let part = remain.get(..length as usize).ok_or_else(malformed)?;
+                        text.push_str(&String::from_utf8_lossy(part));
+                        rest = &remain[length as usize..];
+                    }
+                    match text.trim().parse() {
+                        Ok(address) => addresses.push(address),
+                        Err(_) => debug!("Ignore TXT record {text:?}"),
+                    }
+                }
+            }
+        }
+        Ok(addresses)
+    }
+
+    /// Discover external address by asking resolvers who we are
+    pub struct DnsIPSource {
+        queries: Vec<DnsQuery>,
+        timeout: Duration,
+        ipv4: bool,
+        ipv6: bool,
+    }
+
+    impl DnsIPSource {
+        pub fn new(configure: &DnsConfigure, ipv4: bool, ipv6: bool) -> Self {
+            Self {
+                queries: configure.get_queries(),
+                timeout: configure.get_timeout(),
+                ipv4,
+                ipv6,
+            }
+        }
+
+        async fn query(&self, query: &DnsQuery) -> anyhow::Result<IpAddr> {
+            let id: u16 = rand::thread_rng().gen();
+            let response = exchange(
+                query.server_address()?,
+                &build_query(id, &query.name, query.query_type, query.class),
+                self.timeout,
+                |response| response.len() >= 2 && response[..2] == id.to_be_bytes(),
+            )
+            .await?;
+            parse_response(&response, id, query.query_type)?
+                .into_iter()
+                .find(|address| address.is_ipv6() == query.is_ipv6().unwrap_or(false))
+                .ok_or_else(|| anyhow!("No address in answer of {}", query.name))
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl IPSource for DnsIPSource {
+        async fn get_current_ip(&self) -> anyhow::Result<CurrentIP> {
+            let mut current = CurrentIP::default();
+            for query in &self.queries {
+                let ipv6 = match query.is_ipv6() {
+                    Ok(ipv6) => ipv6,
+                    Err(e) => {
+                        warn!("{e}");
+                        continue;
+                    }
+                };
+                let (enabled, found) = if ipv6 {
+                    (self.ipv6, current.v6.is_some())
+                } else {
+                    (self.ipv4, current.v4.is_some())
+                };
+                if !enabled || found {
+                    continue;
+                }
+                match self.query(query).await {
+                    Ok(address) => current.set(address),
+                    Err(e) => warn!("Query {} from {} error: {e:?}", query.name, query.server),
+                }
+            }
+            if current.is_empty() {
+                return Err(anyhow!("Unable to fetch any IP address from nameservers"));
+            }
+            Ok(current)
+        }
+    }
+}
@@ -0,0 +1,204 @@
+/*
+ ** Copyright (C) 2021-2024 KunoiSayami
+ **
+ ** This file is part of passive-DDNS and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+pub(crate) mod api {
+    use crate::configparser::{CurrentIP, IPSource};
+    use crate::tls::TlsConfigure;
+    use anyhow::anyhow;
+    use log::error;
+    use serde::Deserialize;
+    use serde_json::Value;
+    use std::net::IpAddr;
+    use std::time::Duration;
+    use tap::TapFallible;
+
+    #[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+    #[serde(rename_all = "lowercase")]
+    pub enum FirewallKind {
+        Opnsense,
+        /// pfSense with REST API package v2 installed
+        Pfsense,
+    }
+
+    #[derive(Deserialize)]
+    pub struct FirewallConfigure {
+        enabled: Option<bool>,
+        kind: FirewallKind,
+        route: String,
+        /// API key, OPNsense also requires `secret`
+        key: String,
+        secret: Option<String>,
+        interface: Option<String>,
+        timeout: Option<u64>,
+        tls: Option<TlsConfigure>,
+    }
+
+    impl FirewallConfigure {
+        /// Default is true
+        pub fn get_enabled(&self) -> bool {
+            self.enabled.unwrap_or(true)
+        }
+
+        pub fn get_kind(&self) -> FirewallKind {
+            self.kind
+        }
+
+        /// Interface name, description or device, default is wan
+        pub fn get_interface(&self) -> &str {
+            self.interface.as_deref().unwrap_or("wan")
+        }
+
+        /// Timeout of each request in seconds, default is 10
+        pub fn get_timeout(&self) -> Duration {
+            Duration::from_secs(self.timeout.unwrap_or(10))
+        }
+    }
+
+    fn parse_address(address: &str) -> Option<IpAddr> {
+        address.split('/').next()?.split('%').next()?.parse().ok()
+    }
+
+    /// Device of `interface` in OPNsense `getInterfaceNames`, which maps device to description
+    pub(crate) fn find_opnsense_device(names: &Value, interface: &str) -> Option<String> {
+        names.as_object()?.iter().find_map(|(device, description)| {
+            (device == interface
+                || description
+                    .as_str()
+                    .is_some_and(|description| description.eq_ignore_ascii_case(interface)))
+            .then(|| device.clone())
+        })
+    }
+
+    /// Addresses of `device` in OPNsense `getInterfaceConfig`
+    pub(crate) fn parse_opnsense_config(config: &Value, device: &str) -> Vec<IpAddr> {
+        let interface = &config[device];
+        ["ipv4", "ipv6"]
+            .iter()
+            .filter_map(|family| interface[family].as_array())
+            .flatten()
+            .filter(|entry| !entry["link-local"].as_bool().unwrap_or(false))
+            .filter_map(|entry| parse_address(entry["ipaddr"].as_str()?))
+            .collect()
+    }
+
+    /// Addresses of `interface` in pfSense `/api/v2/status/interfaces`
+    pub(crate) fn parse_pfsense_status(status: &Value, interface: &str) -> Vec<IpAddr> {
+        status["data"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|entry| {
+                ["name", "descr", "hwif"].iter().any(|key| {
+                    entry[key]
+                        .as_str()
+                        .is_some_and(|name| name.eq_ignore_ascii_case(interface))
+                })
+            })
+            .map(|entry| {
+                ["ipaddr", "ipaddrv6"]
+                    .iter()
+                    .filter_map(|key| parse_address(entry[key].as_str()?))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Read WAN address from OPNsense or pfSense API
+    pub struct FirewallIPSource {
+        kind: FirewallKind,
+        client: reqwest::Client,
+        route: String,
+        key: String,
+        secret: Option<String>,
+        interface: String,
+        ipv4: bool,
+        ipv6: bool,
+    }
+
+    impl FirewallIPSource {
+        pub fn new(configure: &FirewallConfigure, ipv4: bool, ipv6: bool) -> anyhow::Result<Self> {
+            if configure.get_kind() == FirewallKind::Opnsense && configure.secret.is_none() {
+                return Err(anyhow!("OPNsense API secret is not set"));
+            }
+            let builder = reqwest::ClientBuilder::new().timeout(configure.get_timeout());
+            Ok(Self {
+                kind: configure.get_kind(),
+                client: configure
+                    .tls
+                    .clone()
+                    .unwrap_or_default()
+                    .apply(builder, "firewall")?
+                    .build()?,
+                route: configure.route.trim_end_matches('/').to_string(),
+                key: configure.key.clone(),
+                secret: configure.secret.clone(),
+                interface: configure.get_interface().to_string(),
+                ipv4,
+                ipv6,
+            })
+        }
+
+        async fn get(&self, path: &str) -> anyhow::Result<Value> {
+            let request = self.client.get(format!("{}{path}", self.route));
+            let request = match self.kind {
+                FirewallKind::Opnsense => request.basic_auth(&self.key, self.secret.as_ref()),
+                FirewallKind::Pfsense => request.header("X-API-Key", &self.key),
+            };
+            Ok(request.send().await?.error_for_status()?.json().await?)
+        }
+
+        async fn opnsense_addresses(&self) -> anyhow::Result<Vec<IpAddr>> {
+            let names = self
+                .get("/api/diagnostics/interface/getInterfaceNames")
+                .await?;
+            let device = find_opnsense_device(&names, &self.interface)
+                .ok_or_else(|| anyhow!("Interface {} not found on OPNsense", self.interface))?;
+            let config = self
+                .get("/api/diagnostics/interface/getInterfaceConfig")
+                .await?;
+            Ok(parse_opnsense_config(&config, &device))
+        }
+
+        async fn pfsense_addresses(&self) -> anyhow::Result<Vec<IpAddr>> {
+            let status = self.get("/api/v2/status/interfaces").await?;
+            Ok(parse_pfsense_status(&status, &self.interface))
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl IPSource for FirewallIPSource {
+        async fn get_current_ip(&self) -> anyhow::Result<CurrentIP> {
+            let addresses = match self.kind {
+                FirewallKind::Opnsense => self.opnsense_addresses().await,
+                FirewallKind::Pfsense => self.pfsense_addresses().await,
+            }
+            .tap_err(|e| {
+                error!(
+                    "Read {:?} interface {} error: {e:?}",
+                    self.kind, self.interface
+                )
+            })?;
+            let current = CurrentIP::first_of(addresses, self.ipv4, self.ipv6);
+            if current.is_empty() {
+                return Err(anyhow!("No address on interface {}", self.interface));
+            }
+            Ok(current)
+        }
+    }
+}
@@ -0,0 +1,320 @@
+/*
+ ** Copyright (C) 2021-2024 KunoiSayami
+ **
+ ** This file is part of passive-DDNS and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+pub(crate) mod api {
+    use crate::configparser::{CurrentIP, IPSource};
+    use crate::soap;
+    use crate::tls::TlsConfigure;
+    use anyhow::anyhow;
+    use log::{debug, error};
+    use rand::Rng;
+    use reqwest::StatusCode;
+    use serde::Deserialize;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::time::Duration;
+    use tap::TapFallible;
+
+    const WAN_IP_CONNECTION: &str = "urn:dslforum-org:service:WANIPConnection:1";
+    const WAN_PPP_CONNECTION: &str = "urn:dslforum-org:service:WANPPPConnection:1";
+
+    #[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+    #[serde(rename_all = "lowercase")]
+    pub enum FritzConnection {
+        /// Cable, fibre and most DSL connections since FRITZ!OS 7
+        #[default]
+        Ip,
+        /// PPPoE session
+        Ppp,
+    }
+
+    impl FritzConnection {
+        fn service(&self) -> (&'static str, &'static str) {
+            match self {
+                FritzConnection::Ip => (WAN_IP_CONNECTION, "/upnp/control/wanipconnection1"),
+                FritzConnection::Ppp => (WAN_PPP_CONNECTION, "/upnp/control/wanpppconn1"),
+            }
+        }
+    }
+
+    #[derive(Deserialize)]
+    pub struct FritzboxConfigure {
+        enabled: Option<bool>,
+        route: Option<String>,
+        user: String,
+        password: String,
+        connection: Option<FritzConnection>,
+        /// Interface identifier appended to delegated prefix, e.g. `::1234:5678:9abc:def0`
+        ipv6_suffix: Option<Ipv6Addr>,
+        timeout: Option<u64>,
+        tls: Option<TlsConfigure>,
+    }
+
+    impl FritzboxConfigure {
+        /// Default is true
+        pub fn get_enabled(&self) -> bool {
+            self.enabled.unwrap_or(true)
+        }
+
+        /// Default is http://fritz.box:49000
+        pub fn get_route(&self) -> &str {
+            self.route.as_deref().unwrap_or("http://fritz.box:49000")
+        }
+
+        /// Default is ip
+        pub fn get_connection(&self) -> FritzConnection {
+            self.connection.unwrap_or_default()
+        }
+
+        /// Timeout of each request in seconds, default is 10
+        pub fn get_timeout(&self) -> Duration {
+            Duration::from_secs(self.timeout.unwrap_or(10))
+        }
+    }
+
+    /// `WWW-Authenticate: Digest ...` challenge (RFC 2617)
+    #[derive(Clone, Debug, Default, PartialEq, Eq)]
+    pub(crate) struct DigestChallenge {
+        pub realm: String,
+        pub nonce: String,
+        pub qop: Option<String>,
+        pub opaque: Option<String>,
+    }
+
+    pub(crate) fn parse_challenge(header: &str) -> Option<DigestChallenge> {
+        let parameters = header.trim().strip_prefix("Digest")?;
+        let mut challenge = DigestChallenge::default();
+        let mut rest = parameters.trim_start();
+        while !rest.is_empty() {
+            let (name, value) = rest.split_once('=')?;
+            let (value, remain) = match value.strip_prefix('"') {
+                Some(quoted) => {
+                    let end = quoted.find('"')?;
+                    (&quoted[..end], &quoted[end + 1..])
+                }
+                None => value.split_once(',').unwrap_or((value, "")),
+            };
+            match name.trim().to_ascii_lowercase().as_str() {
+                "realm" => challenge.realm = value.to_string(),
+                "nonce" => challenge.nonce = value.to_string(),
+                // Only `auth` is supported, `auth-int` is never offered alone
+                "qop" => {
+                    challenge.qop = value
+                        .split(',')
+                        .any(|qop| qop.trim() == "auth")
+                        .then(|| "auth".to_string())
+                }
+                "opaque" => challenge.opaque = Some(value.to_string()),
+                _ => {}
+            }
+            rest = remain.trim_start().trim_start_matches(',').trim_start();
+        }
+        (!challenge.nonce.is_empty()).then_some(challenge)
+    }
+
+    fn md5_hex(data: &str) -> String {
+        format!("{:x}", md5::compute(data))
+    }
+
+    /// `Authorization` header answering `challenge` with MD5 algorithm
+    pub(crate) fn authorization(
+        challenge: &DigestChallenge,
+        user: &str,
+        password: &str,
+        method: &str,
+        uri: &str,
+        nonce_count: u32,
+        cnonce: &str,
+    ) -> String {
+        let ha1 = md5_hex(&format!("{user}:{}:{password}", challenge.realm));
+        let ha2 = md5_hex(&format!("{method}:{uri}"));
+        let nc = format!("{nonce_count:08x}");
+        let mut header = format!(
+            "Digest username=\"{user}\", realm=\"{}\", nonce=\"{}\", uri=\"{uri}\", algorithm=MD5",
+            challenge.realm, challenge.nonce
+        );
+        let response = match &challenge.qop {
+            Some(qop) => {
+                header.push_str(&format!(", qop={qop}, nc={nc}, cnonce=\"{cnonce}\""));
+                md5_hex(&format!(
+                    "{ha1}:{}:{nc}:{cnonce}:{qop}:{ha2}",
+                    challenge.nonce
+                ))
+            }
+            None => md5_hex(&format!("{ha1}:{}:{ha2}", challenge.nonce)),
+        };
+        header.push_str(&format!(", response=\"{response}\""));
+        if let Some(opaque) = &challenge.opaque {
+            header.push_str(&format!(", opaque=\"{opaque}\""));
+        }
+        header
+    }
+
+    /// Combine delegated `prefix` and interface identifier `suffix`
+    pub(crate) fn combine_prefix(prefix: Ipv6Addr, length: u8, suffix: Ipv6Addr) -> Ipv6Addr {
+        let mask = u128::MAX
+            .checked_shl(128 - length.min(128) as u32)
+            .unwrap_or(0);
+        Ipv6Addr::from((u128::from(prefix) & mask) | (u128::from(suffix) & !mask))
+    }
+
+    /// Read WAN address and delegated IPv6 prefix from FRITZ!Box over TR-064
+    pub struct FritzboxIPSource {
+        client: reqwest::Client,
+        route: String,
+        user: String,
+        password: String,
+        connection: FritzConnection,
+        ipv6_suffix: Option<Ipv6Addr>,
+        /// Challenge of last 401 response and nonce count used with it
+        challenge: tokio::sync::Mutex<Option<(DigestChallenge, u32)>>,
+        ipv4: bool,
+        ipv6: bool,
+    }
+
+    impl FritzboxIPSource {
+        pub fn new(configure: &FritzboxConfigure, ipv4: bool, ipv6: bool) -> anyhow::Result<Self> {
+            let builder = reqwest::ClientBuilder::new().timeout(configure.get_timeout());
+            Ok(Self {
+                client: configure
+                    .tls
+                    .clone()
+                    .unwrap_or_default()
+                    .apply(builder, "fritzbox")?
+                    .build()?,
+                route: configure.get_route().trim_end_matches('/').to_string(),
+                user: configure.user.clone(),
+                password: configure.password.clone(),
+                connection: configure.get_connection(),
+                ipv6_suffix: configure.ipv6_suffix,
+                challenge: Default::default(),
+                ipv4,
+                ipv6,
+            })
+        }
+
+        /// Call TR-064 action, answer digest challenge and reuse it for later calls
+        async fn call(&self, service: &str, path: &str, action: &str) -> anyhow::Result<String> {
+            let mut challenge = self.challenge.lock().await;
+            for _ in 0..2 {
+                let mut request = soap::request(
+                    &self.client,
+                    &format!("{}{path}", self.route),
+                    service,
+                    action,
+                    &[],
+                );
+                if let Some((digest, nonce_count)) = challenge.as_mut() {
+                    *nonce_count += 1;
+                    let cnonce = format!("{:016x}", rand::thread_rng().gen::<u64>());
+                    request = request.header(
+                        "Authorization",
+                        authorization(
+                            digest,
+                            &self.user,
+                            &self.password,
+                            "POST",
+                            path,
+                            *nonce_count,
+                            &cnonce,
+                        ),
+                    );
+                }
+                let response = request.send().await?;
+                if response.status() == StatusCode::UNAUTHORIZED {
+                    // First request, or nonce expired
+                    let header = response
+                        .headers()
+                        .get("WWW-Authenticate")
+                        .and_then(|header| header.to_str().ok())
+                        .ok_or_else(|| anyhow!("No digest challenge in 401 response"))?;
+                    debug!("FRITZ!Box digest challenge: {header}");
+                    let digest = parse_challenge(header)
+                        .ok_or_else(|| anyhow!("Unsupported challenge {header}"))?;
+                    *challenge = Some((digest, 0));
+                    continue;
+                }
+                let body = response.text().await?;
+                return soap::parse_response(&body, action).map(String::from);
+            }
+            Err(anyhow!(
+                "FRITZ!Box {action} unauthorized, check user and password"
+            ))
+        }
+
+        async fn external_ipv4(&self) -> anyhow::Result<Ipv4Addr> {
+            let (service, path) = self.connection.service();
+            let response = self.call(service, path, "GetExternalIPAddress").await?;
+            let address = soap::element(&response, "NewExternalIPAddress")
+                .ok_or_else(|| anyhow!("No address in GetExternalIPAddress response"))?;
+            address
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("Invalid external address {address:?}"))
+        }
+
+        async fn external_ipv6(&self) -> anyhow::Result<Ipv6Addr> {
+            let (service, path) = self.connection.service();
+            let Some(suffix) = self.ipv6_suffix else {
+                // Address of the box itself
+                let response = self
+                    .call(service, path, "X_AVM_DE_GetExternalIPv6Address")
+                    .await?;
+                let address = soap::element(&response, "NewExternalIPv6Address")
+                    .ok_or_else(|| anyhow!("No address in GetExternalIPv6Address response"))?;
+                return address
+                    .trim()
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid external address {address:?}"));
+            };
+            let response = self.call(service, path, "X_AVM_DE_GetIPv6Prefix").await?;
+            let prefix = soap::element(&response, "NewIPv6Prefix")
+                .and_then(|prefix| prefix.trim().parse().ok())
+                .ok_or_else(|| anyhow!("No prefix in GetIPv6Prefix response"))?;
+            let length = soap::element(&response, "NewPrefixLength")
+                .and_then(|length| length.trim().parse().ok())
+                .ok_or_else(|| anyhow!("No prefix length in GetIPv6Prefix response"))?;
+            Ok(combine_prefix(prefix, length, suffix))
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl IPSource for FritzboxIPSource {
+        async fn get_current_ip(&self) -> anyhow::Result<CurrentIP> {
+            let mut current = CurrentIP::default();
+            if self.ipv4 {
+                current.v4 = self
+                    .external_ipv4()
+                    .await
+                    .tap_err(|e| error!("Read FRITZ!Box IPv4 address error: {e:?}"))
+                    .ok();
+            }
+            if self.ipv6 {
+                current.v6 = self
+                    .external_ipv6()
+                    .await
+                    .tap_err(|e| error!("Read FRITZ!Box IPv6 address error: {e:?}"))
+                    .ok();
+            }
+            if current.is_empty() {
+                return Err(anyhow!("Unable to fetch any IP address from FRITZ!Box"));
+            }
+            Ok(current)
+        }
+    }
+}
@@ -0,0 +1,386 @@
+/*
+ ** Copyright (C) 2021-2024 KunoiSayami
+ **
+ ** This file is part of passive-DDNS and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+pub(crate) mod api {
+    use crate::configparser::{CurrentIP, IPSource};
+    use crate::soap;
+    use crate::udp::exchange;
+    use anyhow::anyhow;
+    use log::{debug, warn};
+    use rand::Rng;
+    use serde::Deserialize;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+    use std::time::Duration;
+    use tap::TapFallible;
+    use tokio::net::UdpSocket;
+
+    const RETRANSMIT_TIMES: usize = 3;
+    /// Port of NAT-PMP and PCP server on gateway
+    const PCP_PORT: u16 = 5351;
+    const SSDP_ADDRESS: &str = "239.255.255.250:1900";
+    /// Services of IGD able to report external address
+    const WAN_SERVICES: &[&str] = &[
+        "urn:schemas-upnp-org:service:WANIPConnection:2",
+        "urn:schemas-upnp-org:service:WANIPConnection:1",
+        "urn:schemas-upnp-org:service:WANPPPConnection:1",
+    ];
+
+    #[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+    #[serde(rename_all = "lowercase")]
+    pub enum GatewayProtocol {
+        Upnp,
+        Natpmp,
+        Pcp,
+    }
+
+    #[derive(Deserialize)]
+    pub struct GatewayConfigure {
+        enabled: Option<bool>,
+        protocols: Option<Vec<GatewayProtocol>>,
+        gateway: Option<String>,
+        ssdp_address: Option<String>,
+        location: Option<String>,
+        timeout: Option<u64>,
+    }
+
+    impl GatewayConfigure {
+        /// Default is true
+        pub fn get_enabled(&self) -> bool {
+            self.enabled.unwrap_or(true)
+        }
+
+        /// Protocols tried in order, default is upnp, natpmp then pcp
+        pub fn get_protocols(&self) -> Vec<GatewayProtocol> {
+            self.protocols.clone().unwrap_or_else(|| {
+                vec![
+                    GatewayProtocol::Upnp,
+                    GatewayProtocol::Natpmp,
+                    GatewayProtocol::Pcp,
+                ]
+            })
+        }
+
+        /// Timeout of each request in seconds, default is 3
+        pub fn get_timeout(&self) -> Duration {
+            Duration::from_secs(self.timeout.unwrap_or(3))
+        }
+    }
+
+    /// Default IPv4 gateway in `/proc/net/route`
+    fn default_gateway() -> anyhow::Result<Ipv4Addr> {
+        let content = std::fs::read_to_string("/proc/net/route")?;
+        parse_default_gateway(&content).ok_or_else(|| anyhow!("No default gateway found"))
+    }
+
+    pub(crate) fn parse_default_gateway(content: &str) -> Option<Ipv4Addr> {
+        content.lines().skip(1).find_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            if fields.get(1) != Some(&"00000000") {
+                return None;
+            }
+            // Address is written in host byte order
+            u32::from_str_radix(fields.get(2)?, 16)
+                .ok()
+                .map(|gateway| Ipv4Addr::from(u32::from_be(gateway)))
+                .filter(|gateway| !gateway.is_unspecified())
+        })
+    }
+
+    fn parse_address(address: &str, default_port: u16) -> anyhow::Result<SocketAddr> {
+        address
+            .parse::<SocketAddr>()
+            .or_else(|_| {
+                address
+                    .parse::<IpAddr>()
+                    .map(|ip| SocketAddr::new(ip, default_port))
+            })
+            .map_err(|_| anyhow!("Invalid address {address}"))
+    }
+
+    /// NAT-PMP external address request (RFC 6886)
+    pub(crate) fn parse_natpmp_response(response: &[u8]) -> anyhow::Result<Ipv4Addr> {
+        if response.len() < 12 || response[0] != 0 || response[1] != 128 {
+            return Err(anyhow!("Invalid NAT-PMP response"));
+        }
+        let result = u16::from_be_bytes([response[2], response[3]]);
+        if result != 0 {
+            return Err(anyhow!("NAT-PMP request failed with result {result}"));
+        }
+        Ok(Ipv4Addr::new(
+            response[8],
+            response[9],
+            response[10],
+            response[11],
+        ))
+    }
+
+    fn to_pcp_address(address: IpAddr) -> [u8; 16] {
+        match address {
+            IpAddr::V4(v4) => v4.to_ipv6_mapped().octets(),
+            IpAddr::V6(v6) => v6.octets(),
+        }
+    }
+
+    /// PCP MAP request (RFC 6887) of a short lived UDP mapping on discard port,
+    /// gateway assigns its external address in response
+    pub(crate) fn build_pcp_request(client: IpAddr, nonce: [u8; 12], lifetime: u32) -> Vec<u8> {
+        let mut request = vec![2, 1, 0, 0];
+        request.extend_from_slice(&lifetime.to_be_bytes());
+        request.extend_from_slice(&to_pcp_address(client));
+        request.extend_from_slice(&nonce);
+        // UDP, reserved
+        request.extend_from_slice(&[17, 0, 0, 0]);
+        // Internal port and suggested external port
+        request.extend_from_slice(&9u16.to_be_bytes());
+        request.extend_from_slice(&9u16.to_be_bytes());
+        let unspecified = match client {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        request.extend_from_slice(&to_pcp_address(unspecified));
+        request
+    }
+
+    pub(crate) fn parse_pcp_response(response: &[u8], nonce: [u8; 12]) -> anyhow::Result<IpAddr> {
+        if response.len() < 60 || response[0] != 2 || response[1] != 0x81 {
+            return Err(anyhow!("Invalid PCP response"));
+        }
+        if response[3] != 0 {
+            return Err(anyhow!("PCP request failed with result {}", response[3]));
+        }
+        if response[24..36] != nonce {
+            return Err(anyhow!("PCP response nonce mismatch"));
+        }
+        let octets: [u8; 16] = response[44..60].try_into().unwrap();
+        let address = Ipv6Addr::from(octets);
+        Ok(address
+            .to_ipv4_mapped()
+            .map(IpAddr::V4)
+            .unwrap_or(IpAddr::V6(address)))
+    }
+
+    /// `(service type, control URL)` of WAN connection services in device description
+    pub(crate) fn parse_description(description: &str) -> Vec<(String, String)> {
+        let mut services = soap::elements(description, "service")
+            .into_iter()
+            .filter_map(|service| {
+                let service_type = soap::element(service, "serviceType")?.trim();
+                let control_url = soap::element(service, "controlURL")?.trim();
+                WAN_SERVICES
+                    .contains(&service_type)
+                    .then(|| (service_type.to_string(), soap::unescape(control_url)))
+            })
+            .collect::<Vec<_>>();
+        services.sort_by_key(|(service_type, _)| {
+            WAN_SERVICES.iter().position(|known| known == service_type)
+        });
+        services
+    }
+
+    pub(crate) fn build_ssdp_search(search_target: &str) -> String {
+        format!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: {SSDP_ADDRESS}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: {search_target}\r\n\r\n"
+        )
+    }
+
+    /// `LOCATION` header of SSDP response
+    pub(crate) fn parse_ssdp_location(response: &str) -> Option<String> {
+        response.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.trim()
+                .eq_ignore_ascii_case("location")
+                .then(|| value.trim().to_string())
+        })
+    }
+
+    /// Ask the LAN gateway for its external address
+    pub struct GatewayIPSource {
+        protocols: Vec<GatewayProtocol>,
+        gateway: Option<String>,
+        ssdp_address: String,
+        location: Option<String>,
+        timeout: Duration,
+        client: reqwest::Client,
+        ipv4: bool,
+        ipv6: bool,
+    }
+
+    impl GatewayIPSource {
+        pub fn new(configure: &GatewayConfigure, ipv4: bool, ipv6: bool) -> Self {
+            Self {
+                protocols: configure.get_protocols(),
+                gateway: configure.gateway.clone(),
+                ssdp_address: configure
+                    .ssdp_address
+                    .clone()
+                    .unwrap_or_else(|| SSDP_ADDRESS.to_string()),
+                location: configure.location.clone(),
+                timeout: configure.get_timeout(),
+                client: reqwest::Client::builder()
+                    .timeout(configure.get_timeout())
+                    .build()
+                    .unwrap(),
+                ipv4,
+                ipv6,
+            }
+        }
+
+        fn gateway(&self) -> anyhow::Result<SocketAddr> {
+            match &self.gateway {
+                Some(gateway) => parse_address(gateway, PCP_PORT),
+                None => Ok(SocketAddr::new(default_gateway()?.into(), PCP_PORT)),
+            }
+        }
+
+        /// Find device description by SSDP
+        async fn discover(&self) -> anyhow::Result<String> {
+            let server = parse_address(&self.ssdp_address, 1900)?;
+            let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+            let search_target = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+            let mut buffer = [0u8; 2048];
+            for _ in 0..RETRANSMIT_TIMES {
+                socket
+                    .send_to(build_ssdp_search(search_target).as_bytes(), server)
+                    .await?;
+                let deadline = tokio::time::Instant::now() + self.timeout;
+                while let Ok(received) =
+                    tokio::time::timeout_at(deadline, socket.recv_from(&mut buffer)).await
+                {
+                    let (size, peer) = received?;
+                    let response = String::from_utf8_lossy(&buffer[..size]);
+                    match parse_ssdp_location(&response) {
+                        Some(location) => return Ok(location),
+                        None => debug!("Ignore SSDP response without location from {peer}"),
+                    }
+                }
+            }
+            Err(anyhow!("No UPnP gateway found"))
+        }
+
+        async fn query_upnp(&self) -> anyhow::Result<IpAddr> {
+            let location = match &self.location {
+                Some(location) => location.clone(),
+                None => self.discover().await?,
+            };
+            debug!("UPnP gateway description at {location}");
+            let description = self.client.get(&location).send().await?.text().await?;
+            let base = soap::element(&description, "URLBase")
+                .map(str::trim)
+                .filter(|base| !base.is_empty())
+                .unwrap_or(&location);
+            let base = reqwest::Url::parse(base)?;
+            let mut last_error = anyhow!("No WAN connection service in {location}");
+            for (service, control_url) in parse_description(&description) {
+                let control_url = base.join(&control_url)?;
+                match soap::call(
+                    &self.client,
+                    control_url.as_str(),
+                    &service,
+                    "GetExternalIPAddress",
+                    &[],
+                )
+                .await
+                .and_then(|response| {
+                    let address = soap::element(&response, "NewExternalIPAddress")
+                        .ok_or_else(|| anyhow!("No address in GetExternalIPAddress response"))?;
+                    address
+                        .trim()
+                        .parse()
+                        .map_err(|_| anyhow!("Invalid external address {address:?}"))
+                }) {
+                    Ok(address) => return Ok(address),
+                    Err(e) => last_error = e,
+                }
+            }
+            Err(last_error)
+        }
+
+        async fn query_natpmp(&self) -> anyhow::Result<IpAddr> {
+            let response = exchange(self.gateway()?, &[0, 0], self.timeout, |response| {
+                response.len() >= 2 && response[1] == 128
+            })
+            .await?;
+            parse_natpmp_response(&response).map(IpAddr::V4)
+        }
+
+        async fn query_pcp(&self) -> anyhow::Result<IpAddr> {
+            let gateway = self.gateway()?;
+            // Address used to reach gateway, PCP server checks it against source address
+            let probe = UdpSocket::bind(if gateway.is_ipv4() {
+                SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+            } else {
+                SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+            })
+            .await?;
+            probe.connect(gateway).await?;
+            let client = probe.local_addr()?.ip();
+            let nonce: [u8; 12] = rand::thread_rng().gen();
+            let accept = |response: &[u8]| {
+                response.len() >= 36 && response[1] == 0x81 && response[24..36] == nonce
+            };
+            let response = exchange(
+                gateway,
+                &build_pcp_request(client, nonce, 60),
+                self.timeout,
+                accept,
+            )
+            .await?;
+            let address = parse_pcp_response(&response, nonce);
+            // Mapping is only requested to learn external address, delete it right away
+            exchange(
+                gateway,
+                &build_pcp_request(client, nonce, 0),
+                self.timeout,
+                accept,
+            )
+            .await
+            .tap_err(|e| warn!("Delete PCP mapping error: {e:?}"))
+            .ok();
+            address
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl IPSource for GatewayIPSource {
+        async fn get_current_ip(&self) -> anyhow::Result<CurrentIP> {
+            let mut current = CurrentIP::default();
+            for protocol in &self.protocols {
+                let result = match protocol {
+                    GatewayProtocol::Upnp => self.query_upnp().await,
+                    GatewayProtocol::Natpmp => self.query_natpmp().await,
+                    GatewayProtocol::Pcp => self.query_pcp().await,
+                };
+                match result {
+                    Ok(address) => {
+                        debug!("Gateway reported {address} by {protocol:?}");
+                        if (address.is_ipv4() && self.ipv4) || (address.is_ipv6() && self.ipv6) {
+                            current.set(address);
+                            break;
+                        }
+                    }
+                    Err(e) => warn!("Query gateway by {protocol:?} error: {e:?}"),
+                }
+            }
+            if current.is_empty() {
+                return Err(anyhow!("Unable to fetch IP address from gateway"));
+            }
+            Ok(current)
+        }
+    }
+}
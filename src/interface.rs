@@ -0,0 +1,232 @@
+/*
+ ** Copyright (C) 2021-2024 KunoiSayami
+ **
+ ** This file is part of passive-DDNS and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+pub(crate) mod api {
+    use crate::configparser::{CurrentIP, IPSource};
+    use anyhow::anyhow;
+    use serde::Deserialize;
+    #[cfg(unix)]
+    use std::ffi::CStr;
+    #[cfg(unix)]
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    /// `IFA_F_*` flags from `linux/if_addr.h`
+    #[cfg(unix)]
+    const IFA_F_TEMPORARY: u32 = 0x01;
+    #[cfg(unix)]
+    const IFA_F_DADFAILED: u32 = 0x08;
+    #[cfg(unix)]
+    const IFA_F_DEPRECATED: u32 = 0x20;
+    #[cfg(unix)]
+    const IFA_F_TENTATIVE: u32 = 0x40;
+
+    #[derive(Deserialize)]
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub struct InterfaceConfigure {
+        enabled: Option<bool>,
+        name: String,
+        skip_private: Option<bool>,
+        skip_link_local: Option<bool>,
+        skip_deprecated: Option<bool>,
+        skip_temporary: Option<bool>,
+    }
+
+    impl InterfaceConfigure {
+        /// Default is true
+        pub fn get_enabled(&self) -> bool {
+            self.enabled.unwrap_or(true)
+        }
+
+        pub fn get_name(&self) -> &str {
+            &self.name
+        }
+    }
+
+    /// Address of interface with IPv6 flags, flags are 0 if kernel does not report them
+    #[cfg(unix)]
+    #[derive(Clone, Debug)]
+    pub(crate) struct InterfaceAddress {
+        pub(crate) address: IpAddr,
+        pub(crate) flags: u32,
+    }
+
+    /// Read IPv4 and IPv6 addresses via `getifaddrs(3)`
+    #[cfg(unix)]
+    fn getifaddrs(name: &str) -> std::io::Result<Vec<InterfaceAddress>> {
+        let mut addresses = Vec::new();
+        let mut ifap: *mut libc::ifaddrs = std::ptr::null_mut();
+        // SAFETY: ifap is freed by freeifaddrs below
+        if unsafe { libc::getifaddrs(&mut ifap) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut current = ifap;
+        while !current.is_null() {
+            // SAFETY: current is a valid node of list returned by getifaddrs
+            let ifa = unsafe { &*current };
+            current = ifa.ifa_next;
+            if ifa.ifa_addr.is_null()
+                || unsafe { CStr::from_ptr(ifa.ifa_name) }.to_bytes() != name.as_bytes()
+            {
+                continue;
+            }
+            let address = match unsafe { (*ifa.ifa_addr).sa_family } as i32 {
+                libc::AF_INET => {
+                    let sockaddr = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in) };
+                    IpAddr::V4(Ipv4Addr::from(u32::from_be(sockaddr.sin_addr.s_addr)))
+                }
+                libc::AF_INET6 => {
+                    let sockaddr = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in6) };
+                    IpAddr::V6(Ipv6Addr::from(sockaddr.sin6_addr.s6_addr))
+                }
+                _ => continue,
+            };
+            addresses.push(InterfaceAddress { address, flags: 0 });
+        }
+        unsafe { libc::freeifaddrs(ifap) };
+        Ok(addresses)
+    }
+
+    /// Parse `/proc/net/if_inet6`, which contains flags of IPv6 addresses
+    #[cfg(unix)]
+    pub(crate) fn parse_if_inet6(content: &str, name: &str) -> Vec<InterfaceAddress> {
+        content
+            .lines()
+            .filter_map(|line| {
+                let fields = line.split_whitespace().collect::<Vec<_>>();
+                if fields.len() < 6 || fields[5] != name {
+                    return None;
+                }
+                let address = u128::from_str_radix(fields[0], 16).ok()?;
+                let flags = u32::from_str_radix(fields[4], 16).ok()?;
+                Some(InterfaceAddress {
+                    address: IpAddr::V6(Ipv6Addr::from(address)),
+                    flags,
+                })
+            })
+            .collect()
+    }
+
+    #[cfg(unix)]
+    pub struct InterfaceIPSource {
+        name: String,
+        ipv4: bool,
+        ipv6: bool,
+        skip_private: bool,
+        skip_link_local: bool,
+        skip_deprecated: bool,
+        skip_temporary: bool,
+    }
+
+    /// Interface address is read by `getifaddrs(3)`, not available on this platform
+    #[cfg(not(unix))]
+    pub enum InterfaceIPSource {}
+
+    #[cfg(not(unix))]
+    impl InterfaceIPSource {
+        pub fn new(
+            _configure: &InterfaceConfigure,
+            _ipv4: bool,
+            _ipv6: bool,
+        ) -> anyhow::Result<Self> {
+            Err(anyhow!("[interface] is only supported on Unix"))
+        }
+    }
+
+    #[cfg(not(unix))]
+    #[async_trait::async_trait]
+    impl IPSource for InterfaceIPSource {
+        async fn get_current_ip(&self) -> anyhow::Result<CurrentIP> {
+            match *self {}
+        }
+    }
+
+    #[cfg(unix)]
+    impl InterfaceIPSource {
+        pub fn new(configure: &InterfaceConfigure, ipv4: bool, ipv6: bool) -> anyhow::Result<Self> {
+            Ok(Self {
+                name: configure.get_name().to_string(),
+                ipv4,
+                ipv6,
+                skip_private: configure.skip_private.unwrap_or(true),
+                skip_link_local: configure.skip_link_local.unwrap_or(true),
+                skip_deprecated: configure.skip_deprecated.unwrap_or(true),
+                skip_temporary: configure.skip_temporary.unwrap_or(true),
+            })
+        }
+
+        fn is_private(address: &IpAddr) -> bool {
+            match address {
+                IpAddr::V4(address) => address.is_private() || address.is_loopback(),
+                // fc00::/7 unique local address
+                IpAddr::V6(address) => {
+                    (address.segments()[0] & 0xfe00) == 0xfc00 || address.is_loopback()
+                }
+            }
+        }
+
+        fn is_link_local(address: &IpAddr) -> bool {
+            match address {
+                IpAddr::V4(address) => address.is_link_local(),
+                // fe80::/10
+                IpAddr::V6(address) => (address.segments()[0] & 0xffc0) == 0xfe80,
+            }
+        }
+
+        pub(crate) fn accept(&self, address: &InterfaceAddress) -> bool {
+            !((self.skip_private && Self::is_private(&address.address))
+                || (self.skip_link_local && Self::is_link_local(&address.address))
+                || (self.skip_deprecated && address.flags & IFA_F_DEPRECATED != 0)
+                || (self.skip_temporary && address.flags & IFA_F_TEMPORARY != 0)
+                || address.flags & (IFA_F_TENTATIVE | IFA_F_DADFAILED) != 0)
+        }
+
+        fn read_addresses(&self) -> anyhow::Result<Vec<InterfaceAddress>> {
+            let mut addresses = getifaddrs(&self.name)?;
+            // getifaddrs does not report address flags, prefer procfs on Linux
+            if let Ok(content) = std::fs::read_to_string("/proc/net/if_inet6") {
+                addresses.retain(|address| address.address.is_ipv4());
+                addresses.extend(parse_if_inet6(&content, &self.name));
+            }
+            Ok(addresses)
+        }
+    }
+
+    #[cfg(unix)]
+    #[async_trait::async_trait]
+    impl IPSource for InterfaceIPSource {
+        async fn get_current_ip(&self) -> anyhow::Result<CurrentIP> {
+            let addresses = self.read_addresses()?;
+            let current = CurrentIP::first_of(
+                addresses
+                    .iter()
+                    .filter(|address| self.accept(address))
+                    .map(|address| address.address),
+                self.ipv4,
+                self.ipv6,
+            );
+            if current.is_empty() {
+                return Err(anyhow!(
+                    "No usable address found on interface {}",
+                    self.name
+                ));
+            }
+            Ok(current)
+        }
+    }
+}
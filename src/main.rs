@@ -17,53 +17,295 @@
  ** You should have received a copy of the GNU Affero General Public License
  ** along with this program. If not, see <https://www.gnu.org/licenses/>.
  */
+mod address;
+mod backoff;
 mod cloudflare_api;
+mod command;
 mod configparser;
 mod custom_target;
+mod dns;
+mod error;
+mod firewall;
+mod fritzbox;
+mod gateway;
+mod interface;
+mod mikrotik;
+#[cfg(target_os = "linux")]
+mod netlink;
 mod openwrt;
+mod soap;
+mod state;
+mod stun;
 #[cfg(test)]
 mod test;
+mod tls;
+mod udp;
 
-use crate::configparser::NameServer;
+use crate::backoff::Backoff;
+use crate::configparser::parser::ConfigureValue;
+use crate::configparser::{CurrentIP, NameServer, UpdateOutcome, UpdatedRecord};
+#[cfg(target_os = "linux")]
+use crate::netlink::AddressWatcher;
+use crate::state::State;
 use clap::arg;
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
 use std::io::Write as _;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tap::TapFallible;
 
-async fn update_process(current_ip: &str, name_server: &dyn NameServer) -> bool {
-    name_server
-        .update_dns_result(current_ip)
+/// Return changed records if update is success, configure errors are returned
+/// as they will never succeed by retrying
+async fn update_process(
+    current_ip: &CurrentIP,
+    known: &[UpdatedRecord],
+    name_server: &dyn NameServer,
+) -> crate::error::Result<Option<Vec<UpdatedRecord>>> {
+    let name = name_server.name();
+    match name_server
+        .update_dns_result(current_ip, known)
         .await
-        .tap_ok(|result| {
-            if *result {
-                info!("IP change detected, Changed dns ip to {current_ip}");
+        .tap_err(|e| error!("[{name}] Error in getting update from name server: {e:#}"))
+    {
+        Ok(UpdateOutcome::Unchanged) => Ok(Some(vec![])),
+        Ok(UpdateOutcome::Updated(records)) => {
+            info!("[{name}] IP change detected, Changed dns ip to {current_ip}");
+            for record in &records {
+                info!("[{name}] Updated {record}");
             }
-        })
-        .tap_err(|e| error!("Error in getting update from cloudflare: {e:#}"))
-        .is_ok()
+            Ok(Some(records))
+        }
+        Ok(UpdateOutcome::PartialFailure { updated, failed }) => {
+            for record in updated {
+                info!("[{name}] Updated {record}");
+            }
+            for (record, e) in failed {
+                error!("[{name}] Failed to update {record}: {e:#}");
+            }
+            Ok(None)
+        }
+        Err(e @ crate::error::Error::Config(_)) => Err(e),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Update name servers of `pending` with their known records concurrently,
+/// return result of each one, or the first configure error
+async fn update_name_servers(
+    name_servers: &[Arc<dyn NameServer>],
+    pending: Vec<(usize, Vec<UpdatedRecord>)>,
+    current_ip: &CurrentIP,
+) -> crate::error::Result<Vec<(usize, Option<Vec<UpdatedRecord>>)>> {
+    let mut tasks = tokio::task::JoinSet::new();
+    for (index, known) in pending {
+        let name_server = name_servers[index].clone();
+        let current_ip = current_ip.clone();
+        tasks.spawn(async move {
+            (
+                index,
+                update_process(&current_ip, &known, &*name_server).await,
+            )
+        });
+    }
+    let mut results = Vec::new();
+    while let Some(result) = tasks.join_next().await {
+        if let Ok((index, result)) = result.tap_err(|e| error!("Update task error: {e:?}")) {
+            // Remaining tasks are aborted once the set is dropped
+            results.push((index, result?));
+        }
+    }
+    Ok(results)
+}
+
+/// Failure state of a name server or IP source
+#[derive(Clone, Copy, Default)]
+struct FailureState {
+    /// Consecutive failed attempts
+    attempts: u32,
+    failing_since: Option<Instant>,
+}
+
+impl FailureState {
+    fn succeed(&mut self) {
+        *self = Self::default();
+    }
+
+    fn fail(&mut self) {
+        self.attempts += 1;
+        self.failing_since.get_or_insert_with(Instant::now);
+    }
+
+    fn is_failing(&self) -> bool {
+        self.attempts > 0
+    }
+
+    fn check_give_up(&self, name: &str, backoff: &Backoff) {
+        if let Some(since) = self.failing_since {
+            if backoff.should_give_up(since) {
+                error!(
+                    "[{name}] Still failing after {:?}, give up",
+                    since.elapsed()
+                );
+                std::process::exit(backoff.give_up_exit_code());
+            }
+        }
+    }
+}
+
+/// Address change events are only available on Linux
+#[cfg(not(target_os = "linux"))]
+type AddressWatcher = std::convert::Infallible;
+
+/// Sleep for `delay`, return true if woken up by address change event
+#[cfg(not(target_os = "linux"))]
+async fn sleep_or_event(_watcher: &mut Option<AddressWatcher>, delay: Duration) -> bool {
+    tokio::time::sleep(delay).await;
+    false
+}
+
+/// Sleep for `delay`, return true if woken up by address change event
+#[cfg(target_os = "linux")]
+async fn sleep_or_event(watcher: &mut Option<AddressWatcher>, delay: Duration) -> bool {
+    let Some(address_watcher) = watcher else {
+        tokio::time::sleep(delay).await;
+        return false;
+    };
+    let result = tokio::select! {
+        _ = tokio::time::sleep(delay) => return false,
+        result = address_watcher.wait() => result,
+    };
+    match result {
+        Ok(()) => {
+            // Events come in bursts, wait for address to settle
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            true
+        }
+        Err(e) => {
+            error!("Watch address change error, fallback to polling: {e:?}");
+            *watcher = None;
+            false
+        }
+    }
 }
 
 async fn async_main(configure_file: &str) -> anyhow::Result<()> {
-    let cfg_values = configparser::parser::get_configure_value(configure_file).await?;
-    let (name_server, ip_source, duration) = cfg_values;
+    let ConfigureValue {
+        name_servers,
+        ip_source,
+        duration,
+        backoff,
+        state_file,
+        reconcile_interval,
+        watch_interfaces,
+    } = configparser::parser::get_configure_value(configure_file).await?;
+    let duration = Duration::from_secs(duration as u64);
+    let mut state = State::load(state_file).await;
+    #[cfg(not(target_os = "linux"))]
+    let mut watcher: Option<AddressWatcher> = {
+        // Rejected by configure parser
+        debug_assert!(watch_interfaces.is_empty());
+        None
+    };
+    #[cfg(target_os = "linux")]
+    let mut watcher = if watch_interfaces.is_empty() {
+        None
+    } else {
+        info!("Watching address change of {watch_interfaces:?}");
+        AddressWatcher::new(watch_interfaces)
+            .tap_err(|e| error!("Subscribe address change error, fallback to polling: {e:?}"))
+            .ok()
+    };
+
+    let mut ip_source_state = FailureState::default();
+    let mut states = vec![FailureState::default(); name_servers.len()];
+    let fingerprints = name_servers
+        .iter()
+        .map(|name_server| name_server.fingerprint())
+        .collect::<Vec<_>>();
+    let mut next_full_round = Instant::now();
     loop {
-        let current_ip = ip_source.get_current_ip().await?;
-        if !update_process(&current_ip, &*name_server).await {
-            let mut v = true;
-            for retry_times in &[5, 10, 60] {
-                warn!("Sleep {retry_times}s for next request");
-                tokio::time::sleep(Duration::from_secs(*retry_times)).await;
-                if update_process(&current_ip, &*name_server).await {
-                    v = false;
-                    break;
+        let current_ip = match ip_source.get_current_ip().await {
+            Ok(current_ip) => {
+                ip_source_state.succeed();
+                current_ip
+            }
+            Err(e) => {
+                ip_source_state.fail();
+                ip_source_state.check_give_up("ip_source", &backoff);
+                let delay = backoff.delay(ip_source_state.attempts).min(duration);
+                error!("Get current IP error: {e:#}, retry after {delay:?}");
+                if sleep_or_event(&mut watcher, delay).await {
+                    next_full_round = Instant::now();
                 }
+                continue;
             }
-            if v {
-                panic!("Error while updating NameServer DNS record");
+        };
+
+        // Only retry failed name servers until next poll
+        let full_round = Instant::now() >= next_full_round;
+        if full_round {
+            next_full_round = Instant::now() + duration;
+        }
+
+        let mut pending = Vec::new();
+        for (index, name_server) in name_servers.iter().enumerate() {
+            // Failed name servers are always retried
+            if !states[index].is_failing() {
+                if !full_round {
+                    continue;
+                }
+                if state.is_up_to_date(
+                    name_server.name(),
+                    &fingerprints[index],
+                    &current_ip,
+                    reconcile_interval,
+                ) {
+                    debug!("[{}] IP is not changed, skip", name_server.name());
+                    continue;
+                }
+            }
+            pending.push((
+                index,
+                state.known_records(name_server.name(), &fingerprints[index], reconcile_interval),
+            ));
+        }
+        // Name servers without known records check every record
+        let reconciled = pending
+            .iter()
+            .map(|(index, known)| (*index, known.is_empty()))
+            .collect::<std::collections::HashMap<_, _>>();
+        for (index, records) in update_name_servers(&name_servers, pending, &current_ip).await? {
+            if let Some(records) = records {
+                states[index].succeed();
+                state
+                    .update(
+                        name_servers[index].name(),
+                        &fingerprints[index],
+                        &current_ip,
+                        &records,
+                        reconciled[&index],
+                    )
+                    .await;
+            } else {
+                states[index].fail();
+                states[index].check_give_up(name_servers[index].name(), &backoff);
+            }
+        }
+
+        let delay = match states.iter().map(|state| state.attempts).max() {
+            Some(attempts) if attempts > 0 => {
+                let delay = backoff
+                    .delay(attempts)
+                    .min(next_full_round.saturating_duration_since(Instant::now()));
+                warn!("Sleep {delay:?} for next request");
+                delay
             }
+            _ => next_full_round.saturating_duration_since(Instant::now()),
+        };
+        if sleep_or_event(&mut watcher, delay).await {
+            info!("Address change detected, update now");
+            next_full_round = Instant::now();
         }
-        tokio::time::sleep(Duration::from_secs(duration as u64)).await;
     }
 }
 
@@ -0,0 +1,416 @@
+/*
+ ** Copyright (C) 2021-2024 KunoiSayami
+ **
+ ** This file is part of passive-DDNS and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+pub(crate) mod api {
+    use crate::configparser::{CurrentIP, IPSource};
+    use crate::tls::TlsConfigure;
+    use anyhow::anyhow;
+    use log::{debug, error, warn};
+    use serde::Deserialize;
+    use std::collections::HashMap;
+    use std::net::IpAddr;
+    use std::time::Duration;
+    use tap::TapFallible;
+    use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWriteExt as _};
+    use tokio::net::TcpStream;
+
+    /// Attributes of one item printed by RouterOS
+    type Row = HashMap<String, String>;
+
+    #[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+    #[serde(rename_all = "lowercase")]
+    pub enum MikrotikApi {
+        /// REST API, available since RouterOS v7.1
+        #[default]
+        Rest,
+        /// Binary API on port 8728, for RouterOS v6
+        Legacy,
+    }
+
+    #[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+    #[serde(rename_all = "lowercase")]
+    pub enum MikrotikSource {
+        /// Address assigned to `interface` in `/ip/address` and `/ipv6/address`
+        #[default]
+        Address,
+        /// Public address detected by `/ip/cloud`, requires `ddns-enabled`
+        Cloud,
+    }
+
+    #[derive(Deserialize)]
+    pub struct MikrotikConfigure {
+        enabled: Option<bool>,
+        api: Option<MikrotikApi>,
+        source: Option<MikrotikSource>,
+        /// `https://192.168.88.1` for REST, `192.168.88.1:8728` for legacy API
+        route: String,
+        user: String,
+        password: String,
+        interface: Option<String>,
+        timeout: Option<u64>,
+        tls: Option<TlsConfigure>,
+    }
+
+    impl MikrotikConfigure {
+        /// Default is true
+        pub fn get_enabled(&self) -> bool {
+            self.enabled.unwrap_or(true)
+        }
+
+        /// Default is rest
+        pub fn get_api(&self) -> MikrotikApi {
+            self.api.unwrap_or_default()
+        }
+
+        /// Default is address
+        pub fn get_source(&self) -> MikrotikSource {
+            self.source.unwrap_or_default()
+        }
+
+        /// Timeout of each request in seconds, default is 10
+        pub fn get_timeout(&self) -> Duration {
+            Duration::from_secs(self.timeout.unwrap_or(10))
+        }
+    }
+
+    /// Length prefix of API word
+    pub(crate) fn encode_length(length: usize) -> Vec<u8> {
+        let length = length as u32;
+        match length {
+            0..=0x7f => vec![length as u8],
+            0x80..=0x3fff => (length | 0x8000).to_be_bytes()[2..].to_vec(),
+            0x4000..=0x1f_ffff => (length | 0xc0_0000).to_be_bytes()[1..].to_vec(),
+            0x20_0000..=0x0fff_ffff => (length | 0xe000_0000).to_be_bytes().to_vec(),
+            _ => [&[0xf0], &length.to_be_bytes()[..]].concat(),
+        }
+    }
+
+    pub(crate) fn encode_sentence(words: &[&str]) -> Vec<u8> {
+        let mut sentence = Vec::new();
+        for word in words {
+            sentence.extend(encode_length(word.len()));
+            sentence.extend_from_slice(word.as_bytes());
+        }
+        // Empty word ends sentence
+        sentence.push(0);
+        sentence
+    }
+
+    async fn read_length<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<usize> {
+        let first = reader.read_u8().await? as u32;
+        let (mut length, extra) = match first {
+            0x00..=0x7f => (first, 0),
+            0x80..=0xbf => (first & 0x3f, 1),
+            0xc0..=0xdf => (first & 0x1f, 2),
+            0xe0..=0xef => (first & 0x0f, 3),
+            _ => (0, 4),
+        };
+        for _ in 0..extra {
+            length = (length << 8) | reader.read_u8().await? as u32;
+        }
+        Ok(length as usize)
+    }
+
+    pub(crate) async fn read_sentence<R: AsyncRead + Unpin>(
+        reader: &mut R,
+    ) -> anyhow::Result<Vec<String>> {
+        let mut words = Vec::new();
+        loop {
+            let length = read_length(reader).await?;
+            if length == 0 {
+                return Ok(words);
+            }
+            let mut word = vec![0u8; length];
+            reader.read_exact(&mut word).await?;
+            words.push(String::from_utf8_lossy(&word).into_owned());
+        }
+    }
+
+    /// Attributes of `=name=value` words
+    fn attributes(words: &[String]) -> Row {
+        words
+            .iter()
+            .filter_map(|word| word.strip_prefix('=')?.split_once('='))
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    /// Logged in connection of binary API
+    struct LegacyConnection {
+        stream: TcpStream,
+    }
+
+    impl LegacyConnection {
+        async fn connect(address: &str, user: &str, password: &str) -> anyhow::Result<Self> {
+            let mut connection = Self {
+                stream: TcpStream::connect(address).await?,
+            };
+            // Plain text login, introduced in RouterOS 6.43
+            connection
+                .command(&[
+                    "/login",
+                    &format!("=name={user}"),
+                    &format!("=password={password}"),
+                ])
+                .await
+                .map_err(|e| anyhow!("RouterOS login error: {e}"))?;
+            Ok(connection)
+        }
+
+        /// Run command, return attributes of every `!re` reply
+        async fn command(&mut self, words: &[&str]) -> anyhow::Result<Vec<Row>> {
+            self.stream.write_all(&encode_sentence(words)).await?;
+            let mut rows = Vec::new();
+            loop {
+                let sentence = read_sentence(&mut self.stream).await?;
+                match sentence.first().map(String::as_str) {
+                    Some("!re") => rows.push(attributes(&sentence[1..])),
+                    Some("!done") => return Ok(rows),
+                    Some("!trap") | Some("!fatal") => {
+                        let reply = attributes(&sentence[1..]);
+                        return Err(anyhow!(
+                            "RouterOS {} error: {}",
+                            words[0],
+                            reply
+                                .get("message")
+                                .cloned()
+                                .unwrap_or_else(|| sentence[1..].join(" "))
+                        ));
+                    }
+                    _ => debug!("Ignore RouterOS reply {sentence:?}"),
+                }
+            }
+        }
+    }
+
+    enum Backend {
+        Rest {
+            client: reqwest::Client,
+        },
+        Legacy {
+            connection: tokio::sync::Mutex<Option<LegacyConnection>>,
+        },
+    }
+
+    /// Read address from MikroTik RouterOS
+    pub struct MikrotikIPSource {
+        backend: Backend,
+        route: String,
+        user: String,
+        password: String,
+        interface: Option<String>,
+        source: MikrotikSource,
+        timeout: Duration,
+        ipv4: bool,
+        ipv6: bool,
+    }
+
+    impl MikrotikIPSource {
+        pub fn new(configure: &MikrotikConfigure, ipv4: bool, ipv6: bool) -> anyhow::Result<Self> {
+            let backend = match configure.get_api() {
+                MikrotikApi::Rest => {
+                    let builder = reqwest::ClientBuilder::new().timeout(configure.get_timeout());
+                    Backend::Rest {
+                        client: configure
+                            .tls
+                            .clone()
+                            .unwrap_or_default()
+                            .apply(builder, "mikrotik")?
+                            .build()?,
+                    }
+                }
+                MikrotikApi::Legacy => {
+                    warn!("RouterOS binary API sends password in plain text, prefer REST API");
+                    Backend::Legacy {
+                        connection: Default::default(),
+                    }
+                }
+            };
+            if configure.get_source() == MikrotikSource::Address && configure.interface.is_none() {
+                return Err(anyhow!(
+                    "RouterOS interface is required to read its address"
+                ));
+            }
+            Ok(Self {
+                backend,
+                route: configure.route.trim_end_matches('/').to_string(),
+                user: configure.user.clone(),
+                password: configure.password.clone(),
+                interface: configure.interface.clone(),
+                source: configure.get_source(),
+                timeout: configure.get_timeout(),
+                ipv4,
+                ipv6,
+            })
+        }
+
+        /// Items under menu `path`, e.g. `/ip/address`, optionally of `interface` only
+        async fn print(&self, path: &str, interface: Option<&str>) -> anyhow::Result<Vec<Row>> {
+            match &self.backend {
+                Backend::Rest { client } => {
+                    let mut request = client
+                        .get(format!("{}/rest{path}", self.route))
+                        .basic_auth(&self.user, Some(&self.password));
+                    if let Some(interface) = interface {
+                        request = request.query(&[("interface", interface)]);
+                    }
+                    let response = request.send().await?.error_for_status()?;
+                    Ok(parse_rest_rows(response.json().await?))
+                }
+                Backend::Legacy { connection } => {
+                    let mut words = vec![format!("{path}/print")];
+                    if let Some(interface) = interface {
+                        words.push(format!("?interface={interface}"));
+                    }
+                    let words = words.iter().map(String::as_str).collect::<Vec<_>>();
+                    let mut connection = connection.lock().await;
+                    // Reuse logged in connection, reconnect once if it is broken
+                    for _ in 0..2 {
+                        let stream = match connection.as_mut() {
+                            Some(stream) => stream,
+                            None => connection.insert(
+                                tokio::time::timeout(
+                                    self.timeout,
+                                    LegacyConnection::connect(
+                                        &self.route,
+                                        &self.user,
+                                        &self.password,
+                                    ),
+                                )
+                                .await
+                                .map_err(|_| anyhow!("Connect to {} timeout", self.route))??,
+                            ),
+                        };
+                        match tokio::time::timeout(self.timeout, stream.command(&words)).await {
+                            Ok(Ok(rows)) => return Ok(rows),
+                            Ok(Err(e)) if e.is::<std::io::Error>() => {
+                                debug!("RouterOS connection broken: {e}");
+                                connection.take();
+                            }
+                            Ok(Err(e)) => return Err(e),
+                            Err(_) => {
+                                connection.take();
+                                return Err(anyhow!("RouterOS {path} timeout"));
+                            }
+                        }
+                    }
+                    Err(anyhow!("RouterOS connection broken"))
+                }
+            }
+        }
+
+        async fn interface_addresses(&self, path: &str) -> anyhow::Result<Vec<IpAddr>> {
+            Ok(parse_address_rows(
+                &self.print(path, self.interface.as_deref()).await?,
+            ))
+        }
+    }
+
+    /// REST API returns an array of items, or a single object for menus like `/ip/cloud`
+    pub(crate) fn parse_rest_rows(value: serde_json::Value) -> Vec<Row> {
+        let items = match value {
+            serde_json::Value::Array(items) => items,
+            object @ serde_json::Value::Object(_) => vec![object],
+            _ => vec![],
+        };
+        items
+            .into_iter()
+            .filter_map(|item| match item {
+                serde_json::Value::Object(map) => Some(
+                    map.into_iter()
+                        .map(|(name, value)| {
+                            let value = match value {
+                                serde_json::Value::String(value) => value,
+                                value => value.to_string(),
+                            };
+                            (name, value)
+                        })
+                        .collect(),
+                ),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Usable addresses of `/ip/address` or `/ipv6/address` items
+    pub(crate) fn parse_address_rows(rows: &[Row]) -> Vec<IpAddr> {
+        rows.iter()
+            .filter(|row| {
+                ["disabled", "invalid"]
+                    .iter()
+                    .all(|flag| row.get(*flag).map(String::as_str) != Some("true"))
+            })
+            .filter_map(|row| row.get("address")?.split('/').next()?.parse().ok())
+            .filter(|address: &IpAddr| match address {
+                // Link-local address exists on every IPv6 interface
+                IpAddr::V6(v6) => v6.segments()[0] & 0xffc0 != 0xfe80,
+                IpAddr::V4(_) => true,
+            })
+            .collect()
+    }
+
+    #[async_trait::async_trait]
+    impl IPSource for MikrotikIPSource {
+        async fn get_current_ip(&self) -> anyhow::Result<CurrentIP> {
+            let mut current = CurrentIP::default();
+            match self.source {
+                MikrotikSource::Address => {
+                    for (enabled, path) in
+                        [(self.ipv4, "/ip/address"), (self.ipv6, "/ipv6/address")]
+                    {
+                        if !enabled {
+                            continue;
+                        }
+                        match self.interface_addresses(path).await {
+                            Ok(addresses) => {
+                                if let Some(address) = addresses.into_iter().next() {
+                                    current.set(address);
+                                }
+                            }
+                            Err(e) => error!("Read RouterOS {path} error: {e:?}"),
+                        }
+                    }
+                }
+                MikrotikSource::Cloud => {
+                    let rows = self
+                        .print("/ip/cloud", None)
+                        .await
+                        .tap_err(|e| error!("Read RouterOS /ip/cloud error: {e:?}"))?;
+                    let cloud = rows.first().cloned().unwrap_or_default();
+                    for (enabled, key) in [
+                        (self.ipv4, "public-address"),
+                        (self.ipv6, "public-address-ipv6"),
+                    ] {
+                        if let Some(address) = cloud
+                            .get(key)
+                            .filter(|_| enabled)
+                            .and_then(|address| address.parse().ok())
+                        {
+                            current.set(address);
+                        }
+                    }
+                }
+            }
+            if current.is_empty() {
+                return Err(anyhow!("No address found on RouterOS"));
+            }
+            Ok(current)
+        }
+    }
+}
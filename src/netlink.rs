@@ -0,0 +1,149 @@
+/*
+ ** Copyright (C) 2021-2024 KunoiSayami
+ **
+ ** This file is part of passive-DDNS and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use log::debug;
+use std::ffi::CStr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use tokio::io::unix::AsyncFd;
+
+const RTMGRP_IPV4_IFADDR: u32 = 0x10;
+const RTMGRP_IPV6_IFADDR: u32 = 0x100;
+const RTM_NEWADDR: u16 = 20;
+const RTM_DELADDR: u16 = 21;
+/// sizeof(struct nlmsghdr)
+const NLMSG_HDRLEN: usize = 16;
+
+/// Subscribe rtnetlink address events of specified interfaces
+pub struct AddressWatcher {
+    fd: AsyncFd<OwnedFd>,
+    interfaces: Vec<String>,
+}
+
+impl AddressWatcher {
+    pub fn new(interfaces: Vec<String>) -> std::io::Result<Self> {
+        // SAFETY: arguments are valid, returned fd is owned by OwnedFd
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut address: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        address.nl_family = libc::AF_NETLINK as u16;
+        address.nl_groups = RTMGRP_IPV4_IFADDR | RTMGRP_IPV6_IFADDR;
+        // SAFETY: address is a valid sockaddr_nl
+        if unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &address as *const libc::sockaddr_nl as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as u32,
+            )
+        } < 0
+        {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            // SAFETY: fd is owned by OwnedFd and kept open until AsyncFd is dropped
+            fd: unsafe { AsyncFd::register(fd) }?,
+            interfaces,
+        })
+    }
+
+    fn interface_name(index: u32) -> Option<String> {
+        let mut buffer = [0 as libc::c_char; libc::IF_NAMESIZE];
+        // SAFETY: buffer has IF_NAMESIZE bytes as required
+        let name = unsafe { libc::if_indextoname(index, buffer.as_mut_ptr()) };
+        if name.is_null() {
+            return None;
+        }
+        Some(
+            unsafe { CStr::from_ptr(name) }
+                .to_string_lossy()
+                .into_owned(),
+        )
+    }
+
+    /// Wait until address of any watched interface is added or removed
+    pub async fn wait(&mut self) -> std::io::Result<()> {
+        let mut buffer = vec![0u8; 8192];
+        loop {
+            let mut guard = self.fd.readable().await?;
+            let received = match guard.try_io(|fd| {
+                // SAFETY: buffer is valid for buffer.len() bytes
+                let size = unsafe {
+                    libc::recv(
+                        fd.as_raw_fd(),
+                        buffer.as_mut_ptr() as *mut libc::c_void,
+                        buffer.len(),
+                        0,
+                    )
+                };
+                if size < 0 {
+                    Err(std::io::Error::last_os_error())
+                } else {
+                    Ok(size as usize)
+                }
+            }) {
+                // Receive buffer overflowed and events are lost, treat as changed
+                Ok(Err(e)) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                    debug!("Address events overflowed: {e}");
+                    return Ok(());
+                }
+                Ok(result) => result?,
+                Err(_would_block) => continue,
+            };
+
+            for index in parse_address_events(&buffer[..received]) {
+                let name = Self::interface_name(index);
+                debug!("Address changed on interface {index} ({name:?})");
+                if name.is_some_and(|name| self.interfaces.contains(&name)) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// Interface indexes of RTM_NEWADDR and RTM_DELADDR messages in netlink datagram
+pub(crate) fn parse_address_events(mut buffer: &[u8]) -> Vec<u32> {
+    let mut indexes = Vec::new();
+    while buffer.len() >= NLMSG_HDRLEN {
+        let length = u32::from_ne_bytes(buffer[0..4].try_into().unwrap()) as usize;
+        let message_type = u16::from_ne_bytes(buffer[4..6].try_into().unwrap());
+        if length < NLMSG_HDRLEN || length > buffer.len() {
+            break;
+        }
+        // struct ifaddrmsg { u8 family; u8 prefixlen; u8 flags; u8 scope; u32 index; }
+        if matches!(message_type, RTM_NEWADDR | RTM_DELADDR) && length >= NLMSG_HDRLEN + 8 {
+            let payload = &buffer[NLMSG_HDRLEN..];
+            indexes.push(u32::from_ne_bytes(payload[4..8].try_into().unwrap()));
+        }
+        // Messages are aligned to 4 bytes
+        let aligned = (length + 3) & !3;
+        buffer = &buffer[aligned.min(buffer.len())..];
+    }
+    indexes
+}
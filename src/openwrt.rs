@@ -18,16 +18,23 @@
  ** along with this program. If not, see <https://www.gnu.org/licenses/>.
  */
 pub(crate) mod api {
-    use crate::configparser::IPSource;
+    use crate::configparser::{CurrentIP, IPSource};
+    use crate::tls::TlsConfigure;
+    use anyhow::anyhow;
     use log::{error, warn};
     use reqwest::header::HeaderMap;
     use reqwest::StatusCode;
     use serde::{Deserialize, Serialize};
     use std::collections::HashMap;
-    use std::path::Path;
+    use std::net::IpAddr;
+    #[cfg(unix)]
+    use std::os::unix::fs::PermissionsExt as _;
+    use std::path::PathBuf;
     use tap::TapFallible;
     use tokio::io::AsyncWriteExt as _;
-    const DEFAULT_SESSION_FILE: &str = ".session";
+    const DEFAULT_SESSION_FILE: &str = "openwrt-session.json";
+    /// Interfaces reported by LuCI status page
+    const LUCI_INTERFACES: [&str; 2] = ["wan", "wan6"];
 
     pub fn get_current_timestamp() -> u128 {
         let start = std::time::SystemTime::now();
@@ -37,7 +44,7 @@ pub(crate) mod api {
         since_the_epoch.as_millis()
     }
 
-    #[derive(Serialize, Deserialize)]
+    #[derive(Serialize, Deserialize, Clone)]
     struct Cookie {
         key: String,
         value: String,
@@ -64,8 +71,8 @@ pub(crate) mod api {
         }
     }
 
-    #[derive(Serialize, Deserialize, Default)]
-    struct Cookies {
+    #[derive(Serialize, Deserialize, Default, Clone)]
+    pub(crate) struct Cookies {
         cookies: Vec<Cookie>,
     }
 
@@ -88,39 +95,92 @@ pub(crate) mod api {
             cookies.join("; ")
         }
 
-        async fn save_cookies(resp: reqwest::Response) -> std::io::Result<()> {
-            let mut session_file = tokio::fs::OpenOptions::new()
-                .create(true)
-                .write(true)
-                .truncate(true)
-                .open(DEFAULT_SESSION_FILE)
-                .await?;
+        fn len(&self) -> usize {
+            self.cookies.len()
+        }
+    }
 
-            let content = Cookies::from_response(&resp);
+    /// Where LuCI session cookies are kept between requests
+    pub struct SessionStore {
+        /// Cookies are kept in memory only if not set
+        path: Option<PathBuf>,
+        memory: tokio::sync::Mutex<Option<Cookies>>,
+    }
+
+    impl SessionStore {
+        pub fn new(path: Option<PathBuf>) -> Self {
+            Self {
+                path,
+                memory: Default::default(),
+            }
+        }
+
+        pub(crate) async fn save_cookies(&self, cookies: Cookies) -> anyhow::Result<()> {
+            let Some(path) = &self.path else {
+                self.memory.lock().await.replace(cookies);
+                return Ok(());
+            };
+            if let Some(parent) = path
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+            {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            let mut options = tokio::fs::OpenOptions::new();
+            options.create(true).write(true).truncate(true);
+            // Session cookie grants router access, never readable by others.
+            // Other platforms keep default ACLs of the directory
+            #[cfg(unix)]
+            options.mode(0o600);
+            let mut session_file = options.open(path).await?;
+            // Mode only applies to new file, fix files created by older versions
+            #[cfg(unix)]
+            tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).await?;
             session_file
-                .write_all(serde_json::to_string(&content)?.as_bytes())
-                .await
+                .write_all(serde_json::to_string(&cookies)?.as_bytes())
+                .await?;
+            Ok(())
         }
 
-        async fn load_cookies() -> anyhow::Result<Cookies> {
-            //let mut cookies= Cookies::new();
+        pub(crate) async fn load_cookies(&self) -> anyhow::Result<Cookies> {
             log::debug!("Loading cookies");
-            if <&str as AsRef<Path>>::as_ref(&DEFAULT_SESSION_FILE).exists() {
-                let txt = tokio::fs::read_to_string(DEFAULT_SESSION_FILE)
+            let Some(path) = &self.path else {
+                return self
+                    .memory
+                    .lock()
+                    .await
+                    .clone()
+                    .ok_or_else(|| anyhow!("No session in memory"));
+            };
+            if path.exists() {
+                let txt = tokio::fs::read_to_string(path)
                     .await
                     .tap_err(|e| warn!("Read cookie file error: {e:?}"))?;
                 Ok(serde_json::from_str(&txt)
                     .tap_err(|e| warn!("Deserialize cookie file error: {e:?}"))?)
             } else {
-                Err(anyhow::anyhow!(
-                    "Cookie file not found, fallback to default"
-                ))
+                Err(anyhow!("Cookie file not found, fallback to default"))
             }
         }
+    }
 
-        fn len(&self) -> usize {
-            self.cookies.len()
+    /// `$STATE_DIRECTORY` set by systemd `StateDirectory=`, then XDG state directory
+    pub(crate) fn default_state_directory() -> Option<PathBuf> {
+        if let Some(directory) = std::env::var_os("STATE_DIRECTORY") {
+            // Multiple directories are separated by colon, use the first one
+            return directory
+                .to_str()
+                .and_then(|directory| directory.split(':').next())
+                .filter(|directory| !directory.is_empty())
+                .map(PathBuf::from);
         }
+        std::env::var_os("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .filter(|directory| directory.is_absolute())
+            .or_else(|| {
+                std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state"))
+            })
+            .map(|directory| directory.join("passive-ddns"))
     }
 
     struct Configure {
@@ -142,9 +202,47 @@ pub(crate) mod api {
         }
     }
 
+    impl TryFrom<&OpenWRTConfigure> for Configure {
+        type Error = anyhow::Error;
+
+        fn try_from(openwrt: &OpenWRTConfigure) -> anyhow::Result<Self> {
+            let field = |value: &Option<String>, name: &str| {
+                value
+                    .clone()
+                    .ok_or_else(|| anyhow!("OpenWRT {name} is not set"))
+            };
+            Ok(Configure::new(
+                field(openwrt.get_user(), "user")?,
+                field(openwrt.get_password(), "password")?,
+                field(openwrt.get_route(), "route")?,
+            ))
+        }
+    }
+
+    /// Addresses of every interface in configured order, the first address of
+    /// each family is used as default address
+    pub(crate) fn collect_addresses(
+        interfaces: Vec<(String, Vec<IpAddr>)>,
+        ipv4: bool,
+        ipv6: bool,
+    ) -> CurrentIP {
+        let mut current = CurrentIP::default();
+        for (interface, addresses) in interfaces {
+            let entry = CurrentIP::first_of(addresses, ipv4, ipv6);
+            current.v4 = current.v4.or(entry.v4);
+            current.v6 = current.v6.or(entry.v6);
+            current.interfaces.insert(interface, entry);
+        }
+        current
+    }
+
     pub struct Client {
         configure: Configure,
         client: reqwest::Client,
+        session: SessionStore,
+        interfaces: Vec<String>,
+        ipv4: bool,
+        ipv6: bool,
     }
 
     impl Client {
@@ -188,7 +286,8 @@ pub(crate) mod api {
             let status_code = resp.status();
             log::debug!("Status code: {status_code}");
             if status_code == StatusCode::OK || status_code == StatusCode::FOUND {
-                Cookies::save_cookies(resp)
+                self.session
+                    .save_cookies(Cookies::from_response(&resp))
                     .await
                     .tap_err(|e| error!("Save cookie error: {e:?}"))?;
                 Ok(false)
@@ -198,24 +297,36 @@ pub(crate) mod api {
             }
         }
 
-        pub fn new<T>(user: T, password: T, basic_address: T) -> Client
-        where
-            T: Into<String>,
-        {
-            let client = reqwest::ClientBuilder::new()
+        pub fn new(openwrt: &OpenWRTConfigure, ipv4: bool, ipv6: bool) -> anyhow::Result<Client> {
+            let interfaces = openwrt.get_interfaces();
+            // LuCI status page only reports the default WAN interfaces
+            if let Some(interface) = interfaces
+                .iter()
+                .find(|interface| !LUCI_INTERFACES.contains(&interface.as_str()))
+            {
+                return Err(anyhow!(
+                    "LuCI status does not report interface {interface}, only {LUCI_INTERFACES:?} are available, use backend = \"ubus\" instead"
+                ));
+            }
+            let builder = reqwest::ClientBuilder::new()
                 .cookie_store(true)
-                .redirect(reqwest::redirect::Policy::none())
-                .build()
-                .unwrap();
-            let configure = Configure::new(user, password, basic_address);
-            Client { configure, client }
+                .redirect(reqwest::redirect::Policy::none());
+            let client = openwrt.get_tls().apply(builder, "openwrt")?.build()?;
+            Ok(Client {
+                configure: Configure::try_from(openwrt)?,
+                client,
+                session: openwrt.get_session_store(),
+                interfaces,
+                ipv4,
+                ipv6,
+            })
         }
     }
 
     #[async_trait::async_trait]
     impl IPSource for Client {
-        async fn get_current_ip(&self) -> anyhow::Result<String> {
-            let cookies = Cookies::load_cookies().await.unwrap_or_default();
+        async fn get_current_ip(&self) -> anyhow::Result<CurrentIP> {
+            let cookies = self.session.load_cookies().await.unwrap_or_default();
             let need_load_cookie = self.do_login(&cookies).await?;
 
             let mut header_map = HeaderMap::new();
@@ -248,18 +359,228 @@ pub(crate) mod api {
                 .await
                 .tap_err(|e| error!("Parse json error: {e:?}"))?;
 
-            Ok(String::from(
-                content["wan"]["ipaddr"].as_str().unwrap_or_else(|| {
-                    error!("Can't found address {content:?}");
-                    "N/A"
-                }),
-            ))
+            let interfaces = self
+                .interfaces
+                .iter()
+                .map(|interface| {
+                    let status = &content[interface];
+                    if status.is_null() {
+                        warn!("Interface {interface} not found in LuCI status");
+                    }
+                    let addresses = [&status["ipaddr"], &status["ip6addr"]]
+                        .into_iter()
+                        .filter_map(|addr| addr.as_str())
+                        // LuCI reports IPv6 address with prefix length, e.g. `2001:db8::1/64`
+                        .filter_map(|addr| addr.split('/').next()?.parse().ok())
+                        .collect();
+                    (interface.clone(), addresses)
+                })
+                .collect();
+            let current = collect_addresses(interfaces, self.ipv4, self.ipv6);
+            if current.is_empty() {
+                error!("Can't found address {content:?}");
+                return Err(anyhow!("No valid address in OpenWRT status"));
+            }
+            Ok(current)
+        }
+    }
+
+    /// Session id used before login, only allowed to call `session.login`
+    const UBUS_ANONYMOUS_SESSION: &str = "00000000000000000000000000000000";
+    /// ubus status code of `UBUS_STATUS_PERMISSION_DENIED`
+    const UBUS_PERMISSION_DENIED: i64 = 6;
+
+    /// Error of a ubus call, `Unauthorized` means session is expired
+    #[derive(Debug)]
+    pub(crate) enum UbusError {
+        Unauthorized,
+        Other(anyhow::Error),
+    }
+
+    impl From<anyhow::Error> for UbusError {
+        fn from(e: anyhow::Error) -> Self {
+            Self::Other(e)
+        }
+    }
+
+    impl From<reqwest::Error> for UbusError {
+        fn from(e: reqwest::Error) -> Self {
+            Self::Other(e.into())
+        }
+    }
+
+    /// Extract payload of ubus JSON-RPC response, `result` is `[status, payload]`
+    pub(crate) fn parse_ubus_response(
+        response: &serde_json::Value,
+    ) -> Result<serde_json::Value, UbusError> {
+        if let Some(error) = response.get("error") {
+            // rpcd reports expired or invalid session as JSON-RPC error -32002
+            if error["code"].as_i64() == Some(-32002) {
+                return Err(UbusError::Unauthorized);
+            }
+            return Err(anyhow!("ubus error: {error}").into());
+        }
+        match response["result"][0].as_i64() {
+            Some(0) => Ok(response["result"][1].clone()),
+            Some(UBUS_PERMISSION_DENIED) => Err(UbusError::Unauthorized),
+            Some(status) => Err(anyhow!("ubus call failed with status {status}").into()),
+            None => Err(anyhow!("Invalid ubus response: {response}").into()),
         }
     }
 
+    /// Addresses listed in `network.interface.<name> status`
+    pub(crate) fn parse_interface_status(status: &serde_json::Value) -> Vec<IpAddr> {
+        ["ipv4-address", "ipv6-address"]
+            .iter()
+            .filter_map(|key| status[key].as_array())
+            .flatten()
+            .filter_map(|entry| entry["address"].as_str())
+            .filter_map(|address| address.parse().ok())
+            .collect()
+    }
+
+    /// Read WAN address through ubus JSON-RPC (`/ubus`), available since OpenWrt 18.06
+    pub struct UbusClient {
+        configure: Configure,
+        client: reqwest::Client,
+        session: tokio::sync::Mutex<Option<String>>,
+        interfaces: Vec<String>,
+        ipv4: bool,
+        ipv6: bool,
+    }
+
+    impl UbusClient {
+        pub fn new(openwrt: &OpenWRTConfigure, ipv4: bool, ipv6: bool) -> anyhow::Result<Self> {
+            let client = openwrt
+                .get_tls()
+                .apply(reqwest::ClientBuilder::new(), "openwrt")?
+                .build()?;
+            Ok(Self {
+                configure: Configure::try_from(openwrt)?,
+                client,
+                session: Default::default(),
+                interfaces: openwrt.get_interfaces(),
+                ipv4,
+                ipv6,
+            })
+        }
+
+        async fn call(
+            &self,
+            session: &str,
+            object: &str,
+            method: &str,
+            arguments: serde_json::Value,
+        ) -> Result<serde_json::Value, UbusError> {
+            let response: serde_json::Value = self
+                .client
+                .post(format!("{}/ubus", self.configure.basic_address))
+                .json(&serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "id": 1,
+                    "method": "call",
+                    "params": [session, object, method, arguments],
+                }))
+                .send()
+                .await?
+                .json()
+                .await?;
+            parse_ubus_response(&response)
+        }
+
+        async fn login(&self) -> anyhow::Result<String> {
+            log::debug!("Login to ubus");
+            let result = self
+                .call(
+                    UBUS_ANONYMOUS_SESSION,
+                    "session",
+                    "login",
+                    serde_json::json!({
+                        "username": self.configure.user,
+                        "password": self.configure.password,
+                    }),
+                )
+                .await
+                .map_err(|e| match e {
+                    UbusError::Unauthorized => {
+                        anyhow!("ubus login denied, check user and password")
+                    }
+                    UbusError::Other(e) => e,
+                })?;
+            result["ubus_rpc_session"]
+                .as_str()
+                .map(String::from)
+                .ok_or_else(|| anyhow!("No session in ubus login response: {result}"))
+        }
+
+        /// Query interface status, login again if session is expired
+        async fn interface_status(&self, interface: &str) -> anyhow::Result<serde_json::Value> {
+            let mut session = self.session.lock().await;
+            for _ in 0..2 {
+                let sid = match session.as_ref() {
+                    Some(sid) => sid.clone(),
+                    None => session.insert(self.login().await?).clone(),
+                };
+                match self
+                    .call(
+                        &sid,
+                        &format!("network.interface.{interface}"),
+                        "status",
+                        serde_json::json!({}),
+                    )
+                    .await
+                {
+                    Ok(status) => return Ok(status),
+                    Err(UbusError::Unauthorized) => {
+                        log::debug!("ubus session expired");
+                        session.take();
+                    }
+                    Err(UbusError::Other(e)) => return Err(e),
+                }
+            }
+            Err(anyhow!("ubus access to interface {interface} denied"))
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl IPSource for UbusClient {
+        async fn get_current_ip(&self) -> anyhow::Result<CurrentIP> {
+            let mut interfaces = Vec::new();
+            for interface in &self.interfaces {
+                // One uplink down should not stop publishing others
+                match self.interface_status(interface).await {
+                    Ok(status) => {
+                        interfaces.push((interface.clone(), parse_interface_status(&status)))
+                    }
+                    Err(e) => error!("Query interface {interface} status error: {e:?}"),
+                }
+            }
+            let current = collect_addresses(interfaces, self.ipv4, self.ipv6);
+            if current.is_empty() {
+                return Err(anyhow!("No address found in ubus interface status"));
+            }
+            Ok(current)
+        }
+    }
+
+    #[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+    #[serde(rename_all = "lowercase")]
+    pub enum OpenWRTBackend {
+        /// Scrape LuCI status page, removed in newer LuCI releases
+        #[default]
+        Luci,
+        /// ubus JSON-RPC, requires `uhttpd-mod-ubus`
+        Ubus,
+    }
+
     #[derive(Deserialize)]
     pub struct OpenWRTConfigure {
         enabled: bool,
+        backend: Option<OpenWRTBackend>,
+        interfaces: Option<Vec<String>>,
+        session_file: Option<PathBuf>,
+        session_in_memory: Option<bool>,
+        tls: Option<TlsConfigure>,
         route: Option<String>,
         user: Option<String>,
         password: Option<String>,
@@ -270,6 +591,35 @@ pub(crate) mod api {
             self.enabled
         }
 
+        /// Default is luci
+        pub fn get_backend(&self) -> OpenWRTBackend {
+            self.backend.unwrap_or_default()
+        }
+
+        /// Logical interfaces to read, addresses of earlier ones are preferred,
+        /// default is ["wan", "wan6"]
+        pub fn get_interfaces(&self) -> Vec<String> {
+            self.interfaces
+                .clone()
+                .unwrap_or_else(|| vec!["wan".into(), "wan6".into()])
+        }
+
+        /// LuCI session is kept in `session_file`, default is `openwrt-session.json`
+        /// under `$STATE_DIRECTORY` or XDG state directory. Kept in memory only if
+        /// `session_in_memory` is true or no state directory is available.
+        pub fn get_session_store(&self) -> SessionStore {
+            if self.session_in_memory.unwrap_or(false) {
+                return SessionStore::new(None);
+            }
+            SessionStore::new(self.session_file.clone().or_else(|| {
+                default_state_directory().map(|directory| directory.join(DEFAULT_SESSION_FILE))
+            }))
+        }
+
+        pub fn get_tls(&self) -> TlsConfigure {
+            self.tls.clone().unwrap_or_default()
+        }
+
         pub fn get_route(&self) -> &Option<String> {
             &self.route
         }
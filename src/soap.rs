@@ -0,0 +1,136 @@
+/*
+ ** Copyright (C) 2021-2024 KunoiSayami
+ **
+ ** This file is part of passive-DDNS and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use anyhow::anyhow;
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+pub(crate) fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Contents of every `tag` element, namespace prefix is ignored
+pub(crate) fn elements<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let mut found = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let Some(name_end) = rest.find(|c: char| c == '>' || c == '/' || c.is_whitespace()) else {
+            break;
+        };
+        let name = &rest[..name_end];
+        if name.rsplit(':').next() != Some(tag) {
+            continue;
+        }
+        let Some(open_end) = rest.find('>') else {
+            break;
+        };
+        if rest[..open_end].ends_with('/') {
+            found.push("");
+            continue;
+        }
+        let content = &rest[open_end + 1..];
+        let Some(end) = content.find(&format!("</{name}>")) else {
+            break;
+        };
+        found.push(&content[..end]);
+        rest = &content[end..];
+    }
+    found
+}
+
+/// Content of first `tag` element, namespace prefix is ignored
+pub(crate) fn element<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    elements(xml, tag).into_iter().next()
+}
+
+/// Request body of `action` on `service`
+pub(crate) fn envelope(service: &str, action: &str, arguments: &[(&str, &str)]) -> String {
+    let arguments = arguments
+        .iter()
+        .map(|(name, value)| format!("<{name}>{}</{name}>", escape(value)))
+        .collect::<String>();
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?><s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/"><s:Body><u:{action} xmlns:u="{service}">{arguments}</u:{action}></s:Body></s:Envelope>"#
+    )
+}
+
+/// `SOAPAction` header value
+pub(crate) fn action_header(service: &str, action: &str) -> String {
+    format!("\"{service}#{action}\"")
+}
+
+/// Body of `<action>Response`, or error described by SOAP fault
+pub(crate) fn parse_response<'a>(body: &'a str, action: &str) -> anyhow::Result<&'a str> {
+    if let Some(response) = element(body, &format!("{action}Response")) {
+        return Ok(response);
+    }
+    if let Some(fault) = element(body, "Fault") {
+        let description = element(fault, "errorDescription")
+            .or_else(|| element(fault, "faultstring"))
+            .unwrap_or(fault);
+        let code = element(fault, "errorCode").unwrap_or("unknown");
+        return Err(anyhow!(
+            "SOAP {action} fault {code}: {}",
+            unescape(description)
+        ));
+    }
+    Err(anyhow!("Invalid SOAP {action} response"))
+}
+
+/// Request of `action` on `control_url`, authentication can be added by caller
+pub(crate) fn request(
+    client: &reqwest::Client,
+    control_url: &str,
+    service: &str,
+    action: &str,
+    arguments: &[(&str, &str)],
+) -> reqwest::RequestBuilder {
+    client
+        .post(control_url)
+        .header("Content-Type", "text/xml; charset=\"utf-8\"")
+        .header("SOAPAction", action_header(service, action))
+        .body(envelope(service, action, arguments))
+}
+
+/// Call `action` on `control_url` and return body of the response element
+pub(crate) async fn call(
+    client: &reqwest::Client,
+    control_url: &str,
+    service: &str,
+    action: &str,
+    arguments: &[(&str, &str)],
+) -> anyhow::Result<String> {
+    let body = request(client, control_url, service, action, arguments)
+        .send()
+        .await?
+        .text()
+        .await?;
+    parse_response(&body, action).map(String::from)
+}
@@ -0,0 +1,203 @@
+/*
+ ** Copyright (C) 2021-2024 KunoiSayami
+ **
+ ** This file is part of passive-DDNS and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::configparser::{CurrentIP, RecordType, UpdatedRecord};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use tap::TapFallible;
+
+/// Digest of name server fingerprint, avoid saving configure into state file
+fn digest(fingerprint: &str) -> String {
+    ring::digest::digest(&ring::digest::SHA256, fingerprint.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+fn current_timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RecordState {
+    id: Option<String>,
+    content: String,
+    updated_at: u64,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct NameServerState {
+    /// Digest of desired records when addresses are published
+    #[serde(default)]
+    fingerprint: String,
+    /// Last published addresses
+    v4: Option<Ipv4Addr>,
+    v6: Option<Ipv6Addr>,
+    #[serde(default)]
+    interfaces: BTreeMap<String, CurrentIP>,
+    /// Last time name server confirmed every record is up to date
+    last_reconcile: u64,
+    #[serde(default)]
+    records: BTreeMap<String, RecordState>,
+}
+
+/// Last known state of every name server, persisted to `state_file` if configured
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct State {
+    #[serde(default)]
+    name_servers: BTreeMap<String, NameServerState>,
+    #[serde(skip)]
+    path: Option<PathBuf>,
+}
+
+impl State {
+    pub async fn load(path: Option<PathBuf>) -> Self {
+        let mut state = match &path {
+            Some(path) if path.exists() => tokio::fs::read_to_string(path)
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|content| Ok(serde_json::from_str::<State>(&content)?))
+                .tap_err(|e| warn!("Load state file {path:?} error, start over: {e:?}"))
+                .unwrap_or_default(),
+            _ => Default::default(),
+        };
+        state.path = path;
+        state
+    }
+
+    async fn save(&self) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // Write to temporary file then rename, avoid broken state file
+        let temp = path.with_extension("tmp");
+        tokio::fs::write(&temp, serde_json::to_string_pretty(self)?).await?;
+        tokio::fs::rename(&temp, path).await?;
+        Ok(())
+    }
+
+    /// State of name server if desired records are not changed and
+    /// reconciled within `reconcile_interval` seconds
+    fn reconciled(
+        &self,
+        name: &str,
+        fingerprint: &str,
+        reconcile_interval: u64,
+    ) -> Option<&NameServerState> {
+        self.name_servers.get(name).filter(|state| {
+            state.fingerprint == digest(fingerprint)
+                && current_timestamp().saturating_sub(state.last_reconcile) < reconcile_interval
+        })
+    }
+
+    /// Whether name server already published `current_ip` to the desired records
+    /// within `reconcile_interval` seconds
+    pub fn is_up_to_date(
+        &self,
+        name: &str,
+        fingerprint: &str,
+        current_ip: &CurrentIP,
+        reconcile_interval: u64,
+    ) -> bool {
+        self.reconciled(name, fingerprint, reconcile_interval)
+            .is_some_and(|state| {
+                state.v4 == current_ip.v4
+                    && state.v6 == current_ip.v6
+                    && state.interfaces == current_ip.interfaces
+            })
+    }
+
+    /// Records published before, empty if they have to be reconciled
+    pub fn known_records(
+        &self,
+        name: &str,
+        fingerprint: &str,
+        reconcile_interval: u64,
+    ) -> Vec<UpdatedRecord> {
+        let Some(state) = self.reconciled(name, fingerprint, reconcile_interval) else {
+            return vec![];
+        };
+        state
+            .records
+            .iter()
+            .filter_map(|(key, record)| {
+                let (record_type, name) = key.split_once(' ')?;
+                let record_type = record_type.parse::<RecordType>().ok()?;
+                let known = UpdatedRecord::new(name, record_type, &record.content);
+                Some(match &record.id {
+                    Some(id) => known.with_id(id),
+                    None => known,
+                })
+            })
+            .collect()
+    }
+
+    /// Save published addresses, `reconciled` means every record is checked
+    /// instead of being updated by known records
+    pub async fn update(
+        &mut self,
+        name: &str,
+        fingerprint: &str,
+        current_ip: &CurrentIP,
+        records: &[UpdatedRecord],
+        reconciled: bool,
+    ) {
+        let now = current_timestamp();
+        let fingerprint = digest(fingerprint);
+        let state = self.name_servers.entry(name.to_string()).or_default();
+        if state.fingerprint != fingerprint {
+            // Records may be removed from configure
+            state.records.clear();
+            state.fingerprint = fingerprint;
+        }
+        state.v4 = current_ip.v4;
+        state.v6 = current_ip.v6;
+        state.interfaces = current_ip.interfaces.clone();
+        if reconciled {
+            state.last_reconcile = now;
+        }
+        for record in records {
+            state.records.insert(
+                format!("{} {}", record.record_type, record.name),
+                RecordState {
+                    id: record.id.clone(),
+                    content: record.content.clone(),
+                    updated_at: now,
+                },
+            );
+        }
+        self.save()
+            .await
+            .tap_err(|e| warn!("Save state file {:?} error: {e:?}", self.path))
+            .ok();
+    }
+}
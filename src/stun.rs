@@ -0,0 +1,215 @@
+/*
+ ** Copyright (C) 2021-2024 KunoiSayami
+ **
+ ** This file is part of passive-DDNS and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+pub(crate) mod api {
+    use crate::configparser::{CurrentIP, IPSource};
+    use crate::udp::exchange;
+    use anyhow::anyhow;
+    use log::warn;
+    use rand::Rng;
+    use serde::Deserialize;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+    use std::time::Duration;
+
+    const MAGIC_COOKIE: u32 = 0x2112_A442;
+    const BINDING_REQUEST: u16 = 0x0001;
+    const BINDING_SUCCESS_RESPONSE: u16 = 0x0101;
+    const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+    const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+    /// Used by RFC 3489 era servers
+    const ATTR_XOR_MAPPED_ADDRESS_LEGACY: u16 = 0x8020;
+    const HEADER_LENGTH: usize = 20;
+
+    #[derive(Deserialize)]
+    pub struct StunConfigure {
+        enabled: Option<bool>,
+        servers: Option<Vec<String>>,
+        timeout: Option<u64>,
+    }
+
+    impl StunConfigure {
+        /// Default is true
+        pub fn get_enabled(&self) -> bool {
+            self.enabled.unwrap_or(true)
+        }
+
+        pub fn get_servers(&self) -> Vec<String> {
+            self.servers.clone().unwrap_or_else(|| {
+                vec![
+                    "stun.cloudflare.com:3478".to_string(),
+                    "stun.l.google.com:19302".to_string(),
+                ]
+            })
+        }
+
+        /// Timeout of each request in seconds, default is 3
+        pub fn get_timeout(&self) -> Duration {
+            Duration::from_secs(self.timeout.unwrap_or(3))
+        }
+    }
+
+    pub(crate) fn build_binding_request(transaction_id: &[u8; 12]) -> Vec<u8> {
+        let mut request = Vec::with_capacity(HEADER_LENGTH);
+        request.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+        request.extend_from_slice(&0u16.to_be_bytes());
+        request.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        request.extend_from_slice(transaction_id);
+        request
+    }
+
+    fn parse_address(value: &[u8], xor: Option<&[u8; 12]>) -> Option<SocketAddr> {
+        if value.len() < 4 {
+            return None;
+        }
+        let mut port = u16::from_be_bytes([value[2], value[3]]);
+        // XOR-MAPPED-ADDRESS is masked by magic cookie and transaction id
+        let mut mask = [0u8; 16];
+        if let Some(transaction_id) = xor {
+            port ^= (MAGIC_COOKIE >> 16) as u16;
+            mask[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+            mask[4..].copy_from_slice(transaction_id);
+        }
+        let address = match value[1] {
+            0x01 if value.len() >= 8 => {
+                let mut octets: [u8; 4] = value[4..8].try_into().ok()?;
+                octets.iter_mut().zip(mask).for_each(|(o, m)| *o ^= m);
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            0x02 if value.len() >= 20 => {
+                let mut octets: [u8; 16] = value[4..20].try_into().ok()?;
+                octets.iter_mut().zip(mask).for_each(|(o, m)| *o ^= m);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => return None,
+        };
+        Some(SocketAddr::new(address, port))
+    }
+
+    /// Parse mapped address from binding success response, XOR-MAPPED-ADDRESS is preferred
+    pub(crate) fn parse_binding_response(
+        response: &[u8],
+        transaction_id: &[u8; 12],
+    ) -> anyhow::Result<SocketAddr> {
+        if response.len() < HEADER_LENGTH {
+            return Err(anyhow!("STUN response too short"));
+        }
+        let message_type = u16::from_be_bytes([response[0], response[1]]);
+        let length = u16::from_be_bytes([response[2], response[3]]) as usize;
+        if response[4..8] != MAGIC_COOKIE.to_be_bytes() || &response[8..20] != transaction_id {
+            return Err(anyhow!("STUN response transaction mismatch"));
+        }
+        if message_type != BINDING_SUCCESS_RESPONSE {
+            return Err(anyhow!("Unexpected STUN message type {message_type:#06x}"));
+        }
+
+        let mut attributes = &response[HEADER_LENGTH..(HEADER_LENGTH + length).min(response.len())];
+        let mut mapped = None;
+        while attributes.len() >= 4 {
+            let attribute_type = u16::from_be_bytes([attributes[0], attributes[1]]);
+            let attribute_length = u16::from_be_bytes([attributes[2], attributes[3]]) as usize;
+            let Some(value) = attributes.get(4..4 + attribute_length) else {
+                break;
+            };
+            match attribute_type {
+                ATTR_XOR_MAPPED_ADDRESS | ATTR_XOR_MAPPED_ADDRESS_LEGACY => {
+                    if let Some(address) = parse_address(value, Some(transaction_id)) {
+                        return Ok(address);
+                    }
+                }
+                ATTR_MAPPED_ADDRESS => mapped = parse_address(value, None),
+                _ => {}
+            }
+            // Attributes are padded to 4 bytes
+            let padded = (4 + attribute_length + 3) & !3;
+            attributes = &attributes[padded.min(attributes.len())..];
+        }
+        mapped.ok_or_else(|| anyhow!("No mapped address in STUN response"))
+    }
+
+    /// Discover external address by STUN binding request (RFC 5389)
+    pub struct StunIPSource {
+        servers: Vec<String>,
+        timeout: Duration,
+        ipv4: bool,
+        ipv6: bool,
+    }
+
+    impl StunIPSource {
+        pub fn new(configure: &StunConfigure, ipv4: bool, ipv6: bool) -> Self {
+            Self {
+                servers: configure.get_servers(),
+                timeout: configure.get_timeout(),
+                ipv4,
+                ipv6,
+            }
+        }
+
+        async fn binding(&self, server: SocketAddr) -> anyhow::Result<IpAddr> {
+            let transaction_id: [u8; 12] = rand::thread_rng().gen();
+            let response = exchange(
+                server,
+                &build_binding_request(&transaction_id),
+                self.timeout,
+                |response| response.len() >= HEADER_LENGTH && response[8..20] == transaction_id,
+            )
+            .await?;
+            Ok(parse_binding_response(&response, &transaction_id)?.ip())
+        }
+
+        async fn query(&self, ipv6: bool) -> anyhow::Result<IpAddr> {
+            for server in &self.servers {
+                let addresses = match tokio::net::lookup_host(server).await {
+                    Ok(addresses) => addresses,
+                    Err(e) => {
+                        warn!("Resolve STUN server {server} error: {e:?}");
+                        continue;
+                    }
+                };
+                for address in addresses.filter(|address| address.is_ipv6() == ipv6) {
+                    match self.binding(address).await {
+                        Ok(ip) => return Ok(ip),
+                        Err(e) => warn!("Query STUN server {server} ({address}) error: {e:?}"),
+                    }
+                }
+            }
+            Err(anyhow!("No STUN server available"))
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl IPSource for StunIPSource {
+        async fn get_current_ip(&self) -> anyhow::Result<CurrentIP> {
+            let mut current = CurrentIP::default();
+            if self.ipv4 {
+                if let Ok(address) = self.query(false).await {
+                    current.set(address);
+                }
+            }
+            if self.ipv6 {
+                if let Ok(address) = self.query(true).await {
+                    current.set(address);
+                }
+            }
+            if current.is_empty() {
+                return Err(anyhow!("Unable to fetch any IP address from STUN servers"));
+            }
+            Ok(current)
+        }
+    }
+}
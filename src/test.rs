@@ -15,9 +15,54 @@
  ** along with this program. If not, see <https://www.gnu.org/licenses/>.
  */
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod test {
     use crate::configparser::parser::Configure;
 
+    /// Serve HTTP on a local port, every request (with body) is answered by `respond`
+    async fn serve_http(respond: impl Fn(&str) -> String + Send + 'static) -> std::net::SocketAddr {
+        use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buffer = [0u8; 4096];
+                let header_end = loop {
+                    let size = stream.read(&mut buffer).await.unwrap();
+                    request.extend_from_slice(&buffer[..size]);
+                    if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        break end + 4;
+                    }
+                };
+                let length = String::from_utf8_lossy(&request[..header_end])
+                    .lines()
+                    .find_map(|line| {
+                        let (name, value) = line.split_once(':')?;
+                        name.eq_ignore_ascii_case("content-length")
+                            .then(|| value.trim().parse::<usize>().unwrap())
+                    })
+                    .unwrap_or(0);
+                while request.len() < header_end + length {
+                    let size = stream.read(&mut buffer).await.unwrap();
+                    request.extend_from_slice(&buffer[..size]);
+                }
+                let response = respond(&String::from_utf8_lossy(&request));
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        address
+    }
+
+    fn http_ok(body: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
     #[test]
     fn test_configure() {
         let content = r#"[account]
@@ -58,20 +103,1380 @@ token = ""
 
         let cf2 = crate::cloudflare_api::api::Configure::new(
             cf.get_domain().clone().unwrap(),
-            cf.get_token().clone().unwrap(),
-        );
+            cf.get_credentials(),
+            cf.get_record_defaults(),
+        )
+        .unwrap();
 
         for zone in cf2.zones() {
-            if zone.zone_id().eq("ca3d180a0c66ac16da45fad9f7674292") {
-                assert_eq!(
-                    zone.domains(),
-                    &vec!["a.example.com".to_string(), "b.example.com".to_string()]
-                )
-            } else if zone.zone_id().eq("2d9437302c842804ab97f94e657c98af") {
-                assert_eq!(zone.domains(), &vec!["c.example.moe".to_string()])
+            let domains = zone
+                .domains()
+                .iter()
+                .map(|domain| domain.name())
+                .collect::<Vec<_>>();
+            if zone.zone_id() == Some("ca3d180a0c66ac16da45fad9f7674292") {
+                assert_eq!(domains, vec!["a.example.com", "b.example.com"])
+            } else if zone.zone_id() == Some("2d9437302c842804ab97f94e657c98af") {
+                assert_eq!(domains, vec!["c.example.moe"])
             } else {
                 unreachable!()
             }
         }
+
+        use crate::configparser::parser::AccountConfigure;
+        let account: AccountConfigure =
+            toml::from_str("state_file = \"/var/lib/passive-ddns/state.json\"").unwrap();
+        assert_eq!(
+            account.get_state_file(),
+            Some("/var/lib/passive-ddns/state.json".into())
+        );
+        let account: AccountConfigure = toml::from_str(
+            "state_file = \"/var/lib/passive-ddns/state.json\"\nstate_in_memory = true",
+        )
+        .unwrap();
+        assert_eq!(account.get_state_file(), None);
+    }
+
+    #[tokio::test]
+    async fn test_custom_upstream() {
+        use crate::configparser::{CurrentIP, NameServer, RecordType, UpdateOutcome};
+        use crate::custom_target::api::CustomUpstream;
+
+        let address = serve_http(|request| {
+            assert!(request.starts_with("POST /update?key=secret"));
+            assert!(request.contains(r#""data":"198.51.100.4""#));
+            http_ok(r#"{"status": 200}"#)
+        })
+        .await;
+        let configure: Configure = toml::from_str(&format!(
+            r#"[account]
+[cloudflare]
+enabled = false
+[openwrt]
+enabled = false
+route = ""
+user = ""
+password = ""
+[custom_upstream]
+upstream_url = "http://{address}/update?key=secret"
+token = "token""#
+        ))
+        .unwrap();
+        let upstream = CustomUpstream::option_new(&configure).unwrap();
+        let current_ip = CurrentIP {
+            v4: Some("198.51.100.4".parse().unwrap()),
+            ..Default::default()
+        };
+        let UpdateOutcome::Updated(records) =
+            upstream.update_dns_result(&current_ip, &[]).await.unwrap()
+        else {
+            unreachable!()
+        };
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].name, "custom_upstream");
+        assert_eq!(records[0].record_type, RecordType::A);
+    }
+
+    #[test]
+    fn test_record_types() {
+        use crate::cloudflare_api::api::Zone;
+        use crate::configparser::{CurrentIP, RecordType};
+
+        let zone: Zone = toml::from_str(
+            r#"zone_id = "ca3d180a0c66ac16da45fad9f7674292"
+domains = ["a.example.com"]
+types = ["A", "AAAA"]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            zone.types(&zone.domains()[0]),
+            vec![RecordType::A, RecordType::AAAA]
+        );
+
+        let zone: Zone = toml::from_str(
+            r#"zone_id = "ca3d180a0c66ac16da45fad9f7674292"
+domains = ["a.example.com"]"#,
+        )
+        .unwrap();
+        assert_eq!(zone.types(&zone.domains()[0]), vec![RecordType::A]);
+
+        let current = CurrentIP {
+            v4: None,
+            v6: Some("2001:db8::1".parse().unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(current.get(RecordType::A), None);
+        assert_eq!(
+            current.get(RecordType::AAAA),
+            Some("2001:db8::1".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_zone_name_candidates() {
+        use crate::cloudflare_api::api::zone_name_candidates;
+
+        assert_eq!(
+            zone_name_candidates("a.b.example.com"),
+            vec!["a.b.example.com", "b.example.com", "example.com"]
+        );
+        assert_eq!(zone_name_candidates("example.com."), vec!["example.com"]);
+        assert!(zone_name_candidates("localhost").is_empty());
+    }
+
+    #[test]
+    fn test_zone_domains() {
+        use crate::cloudflare_api::api::{Configure, Credentials, RecordDefaults, Zone};
+
+        let zone: Zone = toml::from_str(
+            r#"zone_name = "example.com"
+domains = ["example.com", "a.example.com", "c.example.moe"]"#,
+        )
+        .unwrap();
+        let credentials = Credentials::new(Some("114514".to_string()), Default::default());
+        assert!(
+            Configure::new(vec![zone], credentials.clone(), RecordDefaults::default()).is_err()
+        );
+
+        let zone: Zone = toml::from_str(
+            r#"zone_name = "example.com"
+domains = ["example.com", "a.example.com"]"#,
+        )
+        .unwrap();
+        assert!(Configure::new(vec![zone], credentials, RecordDefaults::default()).is_ok());
+    }
+
+    #[test]
+    fn test_cloudflare_error() {
+        use crate::cloudflare_api::error::{Envelope, Error};
+        use reqwest::StatusCode;
+
+        let envelope: Envelope<serde_json::Value> = serde_json::from_str(
+            r#"{"success": false, "errors": [{"code": 10000, "message": "Authentication error"}], "messages": [], "result": null}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            envelope.into_result(StatusCode::OK),
+            Err(Error::Auth { .. })
+        ));
+
+        let envelope: Envelope<serde_json::Value> = serde_json::from_str(
+            r#"{"success": false, "errors": [{"code": 1004, "message": "DNS Validation Error"}], "messages": [], "result": null}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            envelope.into_result(StatusCode::BAD_REQUEST),
+            Err(Error::Validation { .. })
+        ));
+
+        assert!(matches!(
+            Error::from_response(StatusCode::TOO_MANY_REQUESTS, vec![]),
+            Error::RateLimit { .. }
+        ));
+        assert!(matches!(
+            Error::from_response(StatusCode::NOT_FOUND, vec![]),
+            Error::NotFound { .. }
+        ));
+
+        let envelope: Envelope<Vec<serde_json::Value>> = serde_json::from_str(
+            r#"{"success": true, "errors": [], "messages": [], "result": []}"#,
+        )
+        .unwrap();
+        assert_eq!(envelope.into_result(StatusCode::OK).unwrap(), Some(vec![]));
+    }
+
+    #[tokio::test]
+    async fn test_cloudflare_create_record() {
+        use crate::cloudflare_api::api::{CloudFlareConfigure, RecordState, Zone};
+        use crate::configparser::CurrentIP;
+
+        // Cloudflare API stub, no record exists and creation returns the new record
+        let requests = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let received = requests.clone();
+        let address = serve_http(move |request| {
+            let line = request.lines().next().unwrap().to_string();
+            let body = if line.starts_with("POST") {
+                r#"{"success": true, "errors": [], "messages": [], "result": {"id": "372e67954025e0ba6aaa6d586b9e0b59", "zone_id": "ca3d180a0c66ac16da45fad9f7674292", "name": "a.example.com", "type": "A", "content": "198.51.100.4", "proxied": false, "ttl": 1, "comment": null, "tags": []}}"#
+            } else {
+                r#"{"success": true, "errors": [], "messages": [], "result": []}"#
+            };
+            received.lock().unwrap().push(line);
+            http_ok(body)
+        })
+        .await;
+        let api = format!("http://{address}");
+
+        let zone: Zone = toml::from_str(
+            r#"zone_id = "ca3d180a0c66ac16da45fad9f7674292"
+domains = ["a.example.com"]"#,
+        )
+        .unwrap();
+        let current_ip = CurrentIP {
+            v4: Some("198.51.100.4".parse().unwrap()),
+            ..Default::default()
+        };
+        let session = reqwest::Client::new();
+        let defaults = |content: &str| {
+            toml::from_str::<CloudFlareConfigure>(content)
+                .unwrap()
+                .get_record_defaults()
+        };
+
+        let mut failed = Vec::new();
+        let records = zone
+            .request_domain_record(&session, &api, &current_ip, &[], &defaults(""), &mut failed)
+            .await;
+        assert!(failed.is_empty());
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].state(), RecordState::Created);
+        assert!(requests.lock().unwrap()[1].starts_with("POST "));
+
+        requests.lock().unwrap().clear();
+        let records = zone
+            .request_domain_record(
+                &session,
+                &api,
+                &current_ip,
+                &[],
+                &defaults("auto_create = false"),
+                &mut failed,
+            )
+            .await;
+        assert!(records.is_empty());
+        assert_eq!(failed.len(), 1);
+        assert_eq!(
+            failed[0].1.to_string(),
+            "Record not found and auto create is disabled"
+        );
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_domain_settings() {
+        use crate::cloudflare_api::api::Zone;
+        use crate::configparser::RecordType;
+
+        let zone: Zone = toml::from_str(
+            r#"zone_id = "ca3d180a0c66ac16da45fad9f7674292"
+domains = [
+    "a.example.com",
+    { name = "b.example.com", ttl = 300, proxied = true, comment = "home", tags = ["ddns:true"], types = ["AAAA"] },
+]"#,
+        )
+        .unwrap();
+        let domains = zone.domains();
+        assert_eq!(domains[0].name(), "a.example.com");
+        assert_eq!(zone.types(&domains[0]), vec![RecordType::A]);
+        assert_eq!(domains[1].name(), "b.example.com");
+        assert_eq!(zone.types(&domains[1]), vec![RecordType::AAAA]);
+
+        use crate::cloudflare_api::api::{Configure, Credentials, RecordDefaults};
+        use crate::configparser::NameServer;
+        let fingerprint = |content: &str| {
+            Configure::new(
+                vec![toml::from_str(content).unwrap()],
+                Credentials::new(Some("114514".to_string()), Default::default()),
+                RecordDefaults::default(),
+            )
+            .unwrap()
+            .fingerprint()
+        };
+        let base = fingerprint(
+            r#"zone_id = "ca3d180a0c66ac16da45fad9f7674292"
+domains = ["a.example.com"]"#,
+        );
+        assert_eq!(
+            base,
+            fingerprint(
+                r#"zone_id = "ca3d180a0c66ac16da45fad9f7674292"
+domains = ["a.example.com"]"#
+            )
+        );
+        for content in [
+            r#"zone_id = "ca3d180a0c66ac16da45fad9f7674292"
+domains = ["a.example.com", "b.example.com"]"#,
+            r#"zone_id = "ca3d180a0c66ac16da45fad9f7674292"
+domains = ["a.example.com"]
+types = ["A", "AAAA"]"#,
+            r#"zone_id = "ca3d180a0c66ac16da45fad9f7674292"
+domains = [{ name = "a.example.com", ttl = 300 }]"#,
+        ] {
+            assert_ne!(base, fingerprint(content));
+        }
+
+        use crate::cloudflare_api::api::DNSRecord;
+        let record = || -> DNSRecord {
+            serde_json::from_str(
+                r#"{"id": "372e67954025e0ba6aaa6d586b9e0b59", "zone_id": "ca3d180a0c66ac16da45fad9f7674292", "name": "b.example.com", "type": "AAAA", "content": "2001:db8::1", "proxied": true, "ttl": 300, "comment": "home", "tags": ["ddns:true", "owner:home"]}"#,
+            )
+            .unwrap()
+        };
+        let domain = |settings: &str| -> crate::cloudflare_api::api::Domain {
+            toml::from_str(&format!("name = \"b.example.com\"\n{settings}")).unwrap()
+        };
+        let desired = "ttl = 300\nproxied = true\ncomment = \"home\"";
+        assert!(!domain(desired).reconcile(&mut record(), "2001:db8::1"));
+        assert!(!domain("").reconcile(&mut record(), "2001:db8::1"));
+        assert!(!domain("tags = [\"owner:home\", \"ddns:true\"]")
+            .reconcile(&mut record(), "2001:db8::1"));
+        assert!(domain(desired).reconcile(&mut record(), "2001:db8::2"));
+        for settings in [
+            "ttl = 60",
+            "proxied = false",
+            "comment = \"office\"",
+            "tags = [\"ddns:true\"]",
+        ] {
+            let mut changed = record();
+            assert!(
+                domain(settings).reconcile(&mut changed, "2001:db8::1"),
+                "{settings}"
+            );
+            assert!(
+                !domain(settings).reconcile(&mut changed, "2001:db8::1"),
+                "{settings}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_credentials() {
+        use crate::cloudflare_api::api::CloudFlareConfigure;
+
+        let content = r#"
+[credentials]
+account_a = "token_a"
+
+[[domain]]
+zone_id = "ca3d180a0c66ac16da45fad9f7674292"
+domains = ["a.example.com"]
+credential = "account_a"
+
+[[domain]]
+zone_id = "2d9437302c842804ab97f94e657c98af"
+domains = ["c.example.moe"]
+token = "token_b"
+"#;
+        let cf: CloudFlareConfigure = toml::from_str(content).unwrap();
+        assert!(crate::cloudflare_api::api::Configure::new(
+            cf.get_domain().clone().unwrap(),
+            cf.get_credentials(),
+            cf.get_record_defaults(),
+        )
+        .is_ok());
+
+        let cf: CloudFlareConfigure = toml::from_str(
+            r#"
+[[domain]]
+zone_id = "ca3d180a0c66ac16da45fad9f7674292"
+domains = ["a.example.com"]
+credential = "account_c"
+"#,
+        )
+        .unwrap();
+        assert!(crate::cloudflare_api::api::Configure::new(
+            cf.get_domain().clone().unwrap(),
+            cf.get_credentials(),
+            cf.get_record_defaults(),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_backoff() {
+        use crate::backoff::Backoff;
+        use std::time::Duration;
+
+        let backoff = Backoff::new(
+            Duration::from_secs(5),
+            Duration::from_secs(60),
+            2.0,
+            0.0,
+            None,
+            75,
+        );
+        assert_eq!(backoff.delay(1), Duration::from_secs(5));
+        assert_eq!(backoff.delay(3), Duration::from_secs(20));
+        assert_eq!(backoff.delay(10), Duration::from_secs(60));
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(60));
+        assert!(!backoff.should_give_up(std::time::Instant::now()));
+
+        let backoff = Backoff::new(
+            Duration::from_secs(10),
+            Duration::from_secs(60),
+            2.0,
+            0.5,
+            Some(Duration::ZERO),
+            75,
+        );
+        for _ in 0..100 {
+            let delay = backoff.delay(1);
+            assert!(delay >= Duration::from_secs(5) && delay <= Duration::from_secs(15));
+        }
+        assert!(backoff.should_give_up(std::time::Instant::now()));
+
+        use crate::configparser::parser::AccountConfigure;
+        let account: AccountConfigure = toml::from_str("give_up_after = 0.5").unwrap();
+        assert!(account
+            .get_backoff()
+            .unwrap()
+            .should_give_up(std::time::Instant::now() - Duration::from_secs(1800)));
+        for content in [
+            "give_up_after = -1.0",
+            "give_up_after = nan",
+            "give_up_after = inf",
+            "retry_jitter = nan",
+            "retry_initial = 0",
+            "retry_max_delay = 0",
+        ] {
+            let account: AccountConfigure = toml::from_str(content).unwrap();
+            assert!(account.get_backoff().is_err(), "{content}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_state() {
+        use crate::configparser::{CurrentIP, RecordType, UpdatedRecord};
+        use crate::state::State;
+
+        let path = std::env::temp_dir().join(format!("passive-ddns-{}.json", std::process::id()));
+        let current = CurrentIP {
+            v4: Some("192.0.2.1".parse().unwrap()),
+            v6: None,
+            ..Default::default()
+        };
+
+        let mut state = State::load(Some(path.clone())).await;
+        assert!(!state.is_up_to_date("cloudflare", "a.example.com", &current, 86400));
+        state
+            .update(
+                "cloudflare",
+                "a.example.com",
+                &current,
+                &[UpdatedRecord::new("a.example.com", RecordType::A, "192.0.2.1").with_id("id")],
+                true,
+            )
+            .await;
+        assert!(state.is_up_to_date("cloudflare", "a.example.com", &current, 86400));
+        assert!(!state.is_up_to_date("cloudflare", "a.example.com", &current, 0));
+        assert!(!state.is_up_to_date("custom_upstream", "", &current, 86400));
+        // Desired records changed
+        assert!(!state.is_up_to_date("cloudflare", "b.example.com", &current, 86400));
+        assert!(state
+            .known_records("cloudflare", "b.example.com", 86400)
+            .is_empty());
+
+        let mut state = State::load(Some(path.clone())).await;
+        assert!(state.is_up_to_date("cloudflare", "a.example.com", &current, 86400));
+        let changed = CurrentIP {
+            v4: Some("192.0.2.2".parse().unwrap()),
+            v6: None,
+            ..Default::default()
+        };
+        assert!(!state.is_up_to_date("cloudflare", "a.example.com", &changed, 86400));
+        assert_eq!(
+            state.known_records("cloudflare", "a.example.com", 86400),
+            vec![UpdatedRecord::new("a.example.com", RecordType::A, "192.0.2.1").with_id("id")]
+        );
+        assert!(state
+            .known_records("cloudflare", "a.example.com", 0)
+            .is_empty());
+
+        // Updated by known records only, reconcile is still due later
+        state
+            .update(
+                "cloudflare",
+                "a.example.com",
+                &changed,
+                &[UpdatedRecord::new("a.example.com", RecordType::A, "192.0.2.2").with_id("id")],
+                false,
+            )
+            .await;
+        assert!(state.is_up_to_date("cloudflare", "a.example.com", &changed, 86400));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_interface_address() {
+        use crate::interface::api::{parse_if_inet6, InterfaceConfigure, InterfaceIPSource};
+
+        let content = "\
+20010db8000000000000000000000001 03 40 00 80 pppoe-wan
+20010db80000000011223344556677ff 03 40 00 01 pppoe-wan
+20010db8000000000000000000000002 03 40 00 20 pppoe-wan
+fe800000000000000000000000000001 03 40 20 80 pppoe-wan
+20010db8000000000000000000000003 02 40 00 80 eth0
+";
+        let addresses = parse_if_inet6(content, "pppoe-wan");
+        assert_eq!(addresses.len(), 4);
+
+        let configure: InterfaceConfigure = toml::from_str(r#"name = "pppoe-wan""#).unwrap();
+        let source = InterfaceIPSource::new(&configure, true, true).unwrap();
+        let accepted = addresses
+            .iter()
+            .filter(|address| source.accept(address))
+            .map(|address| address.address.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(accepted, vec!["2001:db8::1"]);
+
+        let configure: InterfaceConfigure = toml::from_str(
+            r#"name = "pppoe-wan"
+skip_temporary = false
+skip_deprecated = false
+skip_link_local = false"#,
+        )
+        .unwrap();
+        let source = InterfaceIPSource::new(&configure, true, true).unwrap();
+        assert_eq!(
+            addresses
+                .iter()
+                .filter(|address| source.accept(address))
+                .count(),
+            4
+        );
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_parse_address_events() {
+        use crate::netlink::parse_address_events;
+
+        let message = |message_type: u16, index: u32| {
+            let mut buffer = Vec::new();
+            // nlmsghdr + ifaddrmsg + 2 bytes padding to check alignment
+            buffer.extend_from_slice(&26u32.to_ne_bytes());
+            buffer.extend_from_slice(&message_type.to_ne_bytes());
+            buffer.extend_from_slice(&[0u8; 10]);
+            buffer.extend_from_slice(&[10, 64, 0, 0]);
+            buffer.extend_from_slice(&index.to_ne_bytes());
+            buffer.extend_from_slice(&[0u8; 2]);
+            buffer.extend_from_slice(&[0u8; 2]);
+            buffer
+        };
+        let mut buffer = message(20, 3);
+        // RTM_NEWLINK is ignored
+        buffer.extend(message(16, 4));
+        buffer.extend(message(21, 5));
+        assert_eq!(parse_address_events(&buffer), vec![3, 5]);
+        assert!(parse_address_events(&buffer[..10]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_stun() {
+        use crate::configparser::IPSource;
+        use crate::stun::api::{StunConfigure, StunIPSource};
+        use tokio::net::UdpSocket;
+
+        // Local STUN responder, replies XOR-MAPPED-ADDRESS of the sender
+        let responder = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = responder.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 576];
+            let (size, peer) = responder.recv_from(&mut buffer).await.unwrap();
+            assert_eq!(size, 20);
+            assert_eq!(&buffer[..2], &[0x00, 0x01]);
+            let std::net::IpAddr::V4(ip) = peer.ip() else {
+                unreachable!()
+            };
+            let mut response = vec![0x01, 0x01, 0x00, 0x0c];
+            response.extend_from_slice(&buffer[4..20]);
+            response.extend_from_slice(&[0x00, 0x20, 0x00, 0x08, 0x00, 0x01]);
+            response.extend_from_slice(&(peer.port() ^ 0x2112).to_be_bytes());
+            response.extend(
+                ip.octets()
+                    .iter()
+                    .zip(0x2112_A442u32.to_be_bytes())
+                    .map(|(o, m)| o ^ m),
+            );
+            responder.send_to(&response, peer).await.unwrap();
+        });
+
+        let configure: StunConfigure =
+            toml::from_str(&format!("servers = [\"{address}\"]\ntimeout = 1")).unwrap();
+        let current = StunIPSource::new(&configure, true, false)
+            .get_current_ip()
+            .await
+            .unwrap();
+        assert_eq!(current.v4, Some(std::net::Ipv4Addr::LOCALHOST));
+        assert_eq!(current.v6, None);
+    }
+
+    #[tokio::test]
+    async fn test_dns_source() {
+        use crate::configparser::IPSource;
+        use crate::dns::api::{build_query, parse_response, DnsConfigure, DnsIPSource, QueryType};
+        use tokio::net::UdpSocket;
+
+        // Local nameserver, answers TXT with a compressed name pointing at the question
+        let responder = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = responder.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 512];
+            let (size, peer) = responder.recv_from(&mut buffer).await.unwrap();
+            let mut response = buffer[..size].to_vec();
+            // QR, RD, RA and one answer
+            response[2..4].copy_from_slice(&[0x81, 0x80]);
+            response[6..8].copy_from_slice(&[0x00, 0x01]);
+            response.extend_from_slice(&[0xc0, 0x0c, 0x00, 0x10, 0x00, 0x03]);
+            response.extend_from_slice(&[0, 0, 0, 0, 0x00, 0x0a, 0x09]);
+            response.extend_from_slice(b"192.0.2.7");
+            responder.send_to(&response, peer).await.unwrap();
+        });
+
+        let configure: DnsConfigure = toml::from_str(&format!(
+            "timeout = 1\nqueries = [{{ name = \"whoami.cloudflare\", type = \"TXT\", class = \"CH\", server = \"{address}\" }}]"
+        ))
+        .unwrap();
+        let current = DnsIPSource::new(&configure, true, true)
+            .get_current_ip()
+            .await
+            .unwrap();
+        assert_eq!(current.v4, Some("192.0.2.7".parse().unwrap()));
+        assert_eq!(current.v6, None);
+
+        // A answer, and an id mismatch
+        let mut response = build_query(7, "myip.opendns.com", QueryType::A, Default::default());
+        response[7] = 1;
+        response.extend_from_slice(&[0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01]);
+        response.extend_from_slice(&[0, 0, 0, 0, 0x00, 0x04, 198, 51, 100, 1]);
+        assert_eq!(
+            parse_response(&response, 7, QueryType::A).unwrap(),
+            vec!["198.51.100.1".parse::<std::net::IpAddr>().unwrap()]
+        );
+        assert!(parse_response(&response, 8, QueryType::A).is_err());
+    }
+
+    #[test]
+    fn test_quorum() {
+        use crate::configparser::parser::select_by_quorum;
+        use std::net::IpAddr;
+
+        let a: IpAddr = "203.0.113.1".parse().unwrap();
+        let b: IpAddr = "203.0.113.2".parse().unwrap();
+        let responses = vec![
+            ("https://a".to_string(), a),
+            ("https://b".to_string(), b),
+            ("https://c".to_string(), a),
+        ];
+        assert_eq!(select_by_quorum(&responses, 2).unwrap(), a);
+        assert!(select_by_quorum(&responses, 3).is_err());
+        assert!(select_by_quorum(&[], 1).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_quorum_early_return() {
+        use crate::configparser::parser::{AccountConfigure, DefaultIPSource, QuorumIPSource};
+        use crate::configparser::IPSource;
+        use tokio::net::TcpListener;
+
+        let answering_address = serve_http(|_| http_ok("198.51.100.7")).await;
+        // Accept connections but never answer
+        let hanging = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let hanging_address = hanging.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut streams = Vec::new();
+            loop {
+                streams.push(hanging.accept().await.unwrap());
+            }
+        });
+
+        let account: AccountConfigure = toml::from_str(&format!(
+            "quorum = 2\nextern_ip_uris = [\"http://{hanging_address}/\", \"http://{answering_address}/a\", \"http://{answering_address}/b\"]"
+        ))
+        .unwrap();
+        let source = QuorumIPSource::new(DefaultIPSource::per_uri(&account), 2, true, false);
+        let current =
+            tokio::time::timeout(std::time::Duration::from_secs(5), source.get_current_ip())
+                .await
+                .unwrap()
+                .unwrap();
+        assert_eq!(current.v4, Some("198.51.100.7".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_quorum_across_sources() {
+        use crate::configparser::parser::QuorumIPSource;
+        use crate::configparser::{CurrentIP, IPSource};
+        use std::sync::Arc;
+
+        struct Fixed(CurrentIP);
+
+        #[async_trait::async_trait]
+        impl IPSource for Fixed {
+            async fn get_current_ip(&self) -> anyhow::Result<CurrentIP> {
+                Ok(self.0.clone())
+            }
+        }
+
+        let fixed = |v4: &str, v6: Option<&str>| -> Arc<dyn IPSource> {
+            Arc::new(Fixed(CurrentIP {
+                v4: Some(v4.parse().unwrap()),
+                v6: v6.map(|v6| v6.parse().unwrap()),
+                ..Default::default()
+            }))
+        };
+        let sources = vec![
+            ("http".to_string(), fixed("198.51.100.7", None)),
+            (
+                "stun".to_string(),
+                fixed("198.51.100.7", Some("2001:db8::1")),
+            ),
+            ("dns".to_string(), fixed("203.0.113.9", Some("2001:db8::1"))),
+        ];
+        let current = QuorumIPSource::new(sources.clone(), 2, true, true)
+            .get_current_ip()
+            .await
+            .unwrap();
+        assert_eq!(current.v4, Some("198.51.100.7".parse().unwrap()));
+        assert_eq!(current.v6, Some("2001:db8::1".parse().unwrap()));
+        assert!(QuorumIPSource::new(sources, 3, true, true)
+            .get_current_ip()
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_address_filter() {
+        use crate::address::api::AddressFilter;
+        use crate::configparser::CurrentIP;
+
+        let filter = AddressFilter::new(vec![]);
+        for address in [
+            "10.1.2.3",
+            "100.64.0.1",
+            "192.168.1.1",
+            "127.0.0.1",
+            "fd00::1",
+            "fe80::1",
+        ] {
+            assert!(!filter.is_acceptable(address.parse().unwrap()), "{address}");
+        }
+        for address in ["1.1.1.1", "2606:4700:4700::1111"] {
+            assert!(filter.is_acceptable(address.parse().unwrap()), "{address}");
+        }
+
+        let current = CurrentIP {
+            v4: Some("100.64.0.1".parse().unwrap()),
+            v6: Some("2606:4700:4700::1111".parse().unwrap()),
+            ..Default::default()
+        };
+        let filtered = filter.filter(current.clone()).unwrap();
+        assert_eq!(filtered.v4, None);
+        assert_eq!(filtered.v6, current.v6);
+        assert!(filter
+            .filter(CurrentIP {
+                v4: current.v4,
+                v6: None,
+                ..Default::default()
+            })
+            .is_err());
+
+        let filter = AddressFilter::new(vec!["100.64.0.0/10".parse().unwrap()]);
+        assert_eq!(filter.filter(current.clone()).unwrap(), current);
+    }
+
+    #[test]
+    fn test_ubus_response() {
+        use crate::openwrt::api::{parse_interface_status, parse_ubus_response, UbusError};
+        use serde_json::json;
+
+        let status = parse_ubus_response(&json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "result": [0, {
+                "up": true,
+                "ipv4-address": [{"address": "198.51.100.2", "mask": 24}],
+                "ipv6-address": [{"address": "2001:db8::2", "mask": 64}],
+            }],
+        }))
+        .unwrap();
+        assert_eq!(
+            parse_interface_status(&status),
+            vec![
+                "198.51.100.2".parse::<std::net::IpAddr>().unwrap(),
+                "2001:db8::2".parse().unwrap()
+            ]
+        );
+
+        assert!(matches!(
+            parse_ubus_response(&json!({"jsonrpc": "2.0", "id": 1, "result": [6]})),
+            Err(UbusError::Unauthorized)
+        ));
+        assert!(matches!(
+            parse_ubus_response(
+                &json!({"jsonrpc": "2.0", "id": 1, "error": {"code": -32002, "message": "Access denied"}})
+            ),
+            Err(UbusError::Unauthorized)
+        ));
+        assert!(matches!(
+            parse_ubus_response(&json!({"jsonrpc": "2.0", "id": 1, "result": [4]})),
+            Err(UbusError::Other(_))
+        ));
+    }
+
+    #[test]
+    fn test_openwrt_interfaces() {
+        use crate::configparser::RecordType;
+        use crate::openwrt::api::collect_addresses;
+
+        let current = collect_addresses(
+            vec![
+                (
+                    "wan_pppoe".to_string(),
+                    vec!["198.51.100.2".parse().unwrap()],
+                ),
+                ("wan6".to_string(), vec!["2001:db8::2".parse().unwrap()]),
+                (
+                    "wwan".to_string(),
+                    vec![
+                        "203.0.113.9".parse().unwrap(),
+                        "2001:db8:1::9".parse().unwrap(),
+                    ],
+                ),
+            ],
+            true,
+            false,
+        );
+        assert_eq!(current.v4, Some("198.51.100.2".parse().unwrap()));
+        assert_eq!(current.v6, None);
+        assert_eq!(
+            current.get_on(Some("wwan"), RecordType::A),
+            Some("203.0.113.9".parse().unwrap())
+        );
+        assert_eq!(current.get_on(Some("wwan"), RecordType::AAAA), None);
+        assert_eq!(current.get_on(Some("lan"), RecordType::A), None);
+        assert_eq!(
+            current.get_on(None, RecordType::A),
+            current.get(RecordType::A)
+        );
+
+        use crate::openwrt::api::{Client, OpenWRTConfigure};
+        let configure = |extra: &str| -> OpenWRTConfigure {
+            toml::from_str(&format!(
+                "enabled = true\nroute = \"http://192.0.2.1\"\nuser = \"root\"\npassword = \"\"\nsession_in_memory = true\n{extra}"
+            ))
+            .unwrap()
+        };
+        assert!(Client::new(&configure(""), true, true).is_ok());
+        assert!(Client::new(&configure("interfaces = [\"wan6\"]"), true, true).is_ok());
+        assert!(Client::new(&configure("interfaces = [\"wan\", \"wwan\"]"), true, true).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_session_store() {
+        use crate::openwrt::api::{Cookies, SessionStore};
+        #[cfg(unix)]
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let directory =
+            std::env::temp_dir().join(format!("passive-ddns-session-{}", std::process::id()));
+        let path = directory.join("openwrt-session.json");
+        let store = SessionStore::new(Some(path.clone()));
+        assert!(store.load_cookies().await.is_err());
+        store.save_cookies(Cookies::default()).await.unwrap();
+        #[cfg(unix)]
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert!(store.load_cookies().await.is_ok());
+        std::fs::remove_dir_all(directory).unwrap();
+
+        let store = SessionStore::new(None);
+        assert!(store.load_cookies().await.is_err());
+        store.save_cookies(Cookies::default()).await.unwrap();
+        assert!(store.load_cookies().await.is_ok());
+    }
+
+    #[test]
+    fn test_tls_pin() {
+        use crate::tls::{parse_fingerprint, PinnedCertVerifier, TlsConfigure};
+
+        // SHA-256 of empty input
+        let fingerprint = "E3:B0:C4:42:98:FC:1C:14:9A:FB:F4:C8:99:6F:B9:24:\
+                           27:AE:41:E4:64:9B:93:4C:A4:95:99:1B:78:52:B8:55";
+        let pin = parse_fingerprint(fingerprint).unwrap();
+        assert_eq!(
+            parse_fingerprint("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")
+                .unwrap(),
+            pin
+        );
+        assert!(parse_fingerprint("e3b0c442").is_err());
+        assert!(parse_fingerprint(&"zz".repeat(32)).is_err());
+
+        let verifier = PinnedCertVerifier::new(vec![pin]);
+        assert!(verifier.is_pinned(b""));
+        assert!(!verifier.is_pinned(b"certificate"));
+
+        let configure: TlsConfigure =
+            toml::from_str(&format!("pin_sha256 = [\"{fingerprint}\"]")).unwrap();
+        assert!(configure
+            .apply(reqwest::ClientBuilder::new(), "test")
+            .is_ok());
+        let configure: TlsConfigure = toml::from_str("pin_sha256 = [\"00\"]").unwrap();
+        assert!(configure
+            .apply(reqwest::ClientBuilder::new(), "test")
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_gateway() {
+        use crate::configparser::IPSource;
+        use crate::gateway::api::{parse_default_gateway, GatewayConfigure, GatewayIPSource};
+        use tokio::net::UdpSocket;
+
+        assert_eq!(
+            parse_default_gateway(
+                "Iface\tDestination\tGateway\tFlags\n\
+                 eth0\t0001A8C0\t00000000\t0001\n\
+                 eth0\t00000000\t0101A8C0\t0003\n"
+            ),
+            Some("192.168.1.1".parse().unwrap())
+        );
+
+        // Local IGD, serves device description and GetExternalIPAddress
+        let http_address = serve_http(|request| {
+            let body = if request.starts_with("GET /rootDesc.xml") {
+                "<?xml version=\"1.0\"?><root><device><serviceList>\
+                 <service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>\
+                 <controlURL>/ctl/L3F</controlURL></service>\
+                 <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+                 <controlURL>/ctl/IPConn</controlURL></service>\
+                 </serviceList></device></root>"
+            } else {
+                assert!(request.starts_with("POST /ctl/IPConn"));
+                assert!(request.contains("WANIPConnection:1#GetExternalIPAddress"));
+                "<?xml version=\"1.0\"?><s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\">\
+                 <s:Body><u:GetExternalIPAddressResponse xmlns:u=\"urn:schemas-upnp-org:service:WANIPConnection:1\">\
+                 <NewExternalIPAddress>198.51.100.7</NewExternalIPAddress>\
+                 </u:GetExternalIPAddressResponse></s:Body></s:Envelope>"
+            };
+            http_ok(body)
+        })
+        .await;
+
+        // Local SSDP, NAT-PMP and PCP responder
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let udp_address = udp.local_addr().unwrap();
+        let pcp_requests = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let requests = pcp_requests.clone();
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 1100];
+            loop {
+                let (size, peer) = udp.recv_from(&mut buffer).await.unwrap();
+                let request = &buffer[..size];
+                let response = if request.starts_with(b"M-SEARCH") {
+                    format!(
+                        "HTTP/1.1 200 OK\r\nST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+                         LOCATION: http://{http_address}/rootDesc.xml\r\n\r\n"
+                    )
+                    .into_bytes()
+                } else if request == [0, 0] {
+                    vec![0, 128, 0, 0, 0, 0, 0, 1, 203, 0, 113, 8]
+                } else {
+                    assert_eq!(request.len(), 60);
+                    assert_eq!(&request[..2], &[2, 1]);
+                    // Lifetime and nonce
+                    requests
+                        .lock()
+                        .unwrap()
+                        .push((request[4..8].to_vec(), request[24..36].to_vec()));
+                    let mut response = vec![2, 0x81, 0, 0];
+                    response.extend_from_slice(&request[4..8]);
+                    response.extend_from_slice(&[0, 0, 0, 1]);
+                    response.extend_from_slice(&[0; 12]);
+                    response.extend_from_slice(&request[24..44]);
+                    response.extend_from_slice(
+                        &"203.0.113.9"
+                            .parse::<std::net::Ipv4Addr>()
+                            .unwrap()
+                            .to_ipv6_mapped()
+                            .octets(),
+                    );
+                    response
+                };
+                udp.send_to(&response, peer).await.unwrap();
+            }
+        });
+
+        for (protocol, expected) in [
+            ("upnp", "198.51.100.7"),
+            ("natpmp", "203.0.113.8"),
+            ("pcp", "203.0.113.9"),
+        ] {
+            let configure: GatewayConfigure = toml::from_str(&format!(
+                "protocols = [\"{protocol}\"]\ngateway = \"{udp_address}\"\n\
+                 ssdp_address = \"{udp_address}\"\ntimeout = 1"
+            ))
+            .unwrap();
+            let current = GatewayIPSource::new(&configure, true, false)
+                .get_current_ip()
+                .await
+                .unwrap();
+            assert_eq!(current.v4, Some(expected.parse().unwrap()), "{protocol}");
+        }
+        // Mapping is deleted with the same nonce
+        let pcp_requests = pcp_requests.lock().unwrap();
+        assert_eq!(pcp_requests.len(), 2);
+        assert_eq!(pcp_requests[0].0, 60u32.to_be_bytes());
+        assert_eq!(pcp_requests[1].0, 0u32.to_be_bytes());
+        assert_eq!(pcp_requests[0].1, pcp_requests[1].1);
+    }
+
+    #[tokio::test]
+    async fn test_mikrotik() {
+        use crate::configparser::IPSource;
+        use crate::mikrotik::api::{
+            encode_length, encode_sentence, parse_address_rows, parse_rest_rows, read_sentence,
+            MikrotikConfigure, MikrotikIPSource,
+        };
+        use tokio::io::AsyncWriteExt as _;
+        use tokio::net::TcpListener;
+
+        assert_eq!(encode_length(0x7f), vec![0x7f]);
+        assert_eq!(encode_length(0x80), vec![0x80, 0x80]);
+        assert_eq!(encode_length(0x4000), vec![0xc0, 0x40, 0x00]);
+        let word = "x".repeat(0x4000);
+        let sentence = encode_sentence(&["/login", &word]);
+        assert_eq!(
+            read_sentence(&mut sentence.as_slice()).await.unwrap(),
+            vec!["/login".to_string(), word]
+        );
+
+        let rows = parse_rest_rows(serde_json::json!([
+            {".id": "*1", "address": "192.168.88.1/24", "interface": "ether1", "disabled": "true"},
+            {".id": "*2", "address": "198.51.100.3/24", "interface": "ether1", "disabled": "false"},
+            {".id": "*3", "address": "fe80::1/64", "interface": "ether1"},
+            {".id": "*4", "address": "2001:db8::3/64", "interface": "ether1", "invalid": false},
+        ]));
+        assert_eq!(
+            parse_address_rows(&rows),
+            vec![
+                "198.51.100.3".parse::<std::net::IpAddr>().unwrap(),
+                "2001:db8::3".parse().unwrap()
+            ]
+        );
+
+        // Local binary API, login then print /ip/address of ether1
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let login = read_sentence(&mut stream).await.unwrap();
+            assert_eq!(login, vec!["/login", "=name=ddns", "=password=secret"]);
+            stream
+                .write_all(&encode_sentence(&["!done"]))
+                .await
+                .unwrap();
+            let print = read_sentence(&mut stream).await.unwrap();
+            assert_eq!(print, vec!["/ip/address/print", "?interface=ether1"]);
+            let mut reply =
+                encode_sentence(&["!re", "=address=198.51.100.4/24", "=disabled=false"]);
+            reply.extend(encode_sentence(&["!done"]));
+            stream.write_all(&reply).await.unwrap();
+        });
+
+        let configure: MikrotikConfigure = toml::from_str(&format!(
+            "api = \"legacy\"\nroute = \"{address}\"\nuser = \"ddns\"\npassword = \"secret\"\n\
+             interface = \"ether1\"\ntimeout = 1"
+        ))
+        .unwrap();
+        let current = MikrotikIPSource::new(&configure, true, false)
+            .unwrap()
+            .get_current_ip()
+            .await
+            .unwrap();
+        assert_eq!(current.v4, Some("198.51.100.4".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_firewall() {
+        use crate::firewall::api::{
+            find_opnsense_device, parse_opnsense_config, parse_pfsense_status,
+        };
+        use serde_json::json;
+        use std::net::IpAddr;
+
+        let names = json!({"igb0": "LAN", "pppoe0": "WAN"});
+        assert_eq!(
+            find_opnsense_device(&names, "wan").as_deref(),
+            Some("pppoe0")
+        );
+        assert_eq!(
+            find_opnsense_device(&names, "igb0").as_deref(),
+            Some("igb0")
+        );
+        assert_eq!(find_opnsense_device(&names, "opt1"), None);
+        let config = json!({
+            "pppoe0": {
+                "ipv4": [{"ipaddr": "198.51.100.5", "subnetbits": 32}],
+                "ipv6": [
+                    {"ipaddr": "fe80::1%pppoe0", "subnetbits": 64, "link-local": true},
+                    {"ipaddr": "2001:db8::5", "subnetbits": 128, "link-local": false},
+                ],
+            },
+        });
+        assert_eq!(
+            parse_opnsense_config(&config, "pppoe0"),
+            vec![
+                "198.51.100.5".parse::<IpAddr>().unwrap(),
+                "2001:db8::5".parse().unwrap()
+            ]
+        );
+
+        let status = json!({
+            "code": 200,
+            "data": [
+                {"name": "lan", "descr": "LAN", "hwif": "em1", "ipaddr": "192.168.1.1"},
+                {"name": "wan", "descr": "WAN", "hwif": "em0", "ipaddr": "198.51.100.6", "ipaddrv6": ""},
+            ],
+        });
+        assert_eq!(
+            parse_pfsense_status(&status, "WAN"),
+            vec!["198.51.100.6".parse::<IpAddr>().unwrap()]
+        );
+        assert!(parse_pfsense_status(&status, "opt1").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_fritzbox() {
+        use crate::configparser::IPSource;
+        use crate::fritzbox::api::{
+            authorization, combine_prefix, parse_challenge, FritzboxConfigure, FritzboxIPSource,
+        };
+
+        // Example of RFC 2617 section 3.5
+        let challenge = parse_challenge(
+            "Digest realm=\"testrealm@host.com\", qop=\"auth,auth-int\", \
+             nonce=\"dcd98b7102dd2f0e8b11d0f600bfb0c093\", \
+             opaque=\"5ccc069c403ebaf9f0171e9517f40e41\"",
+        )
+        .unwrap();
+        assert_eq!(challenge.realm, "testrealm@host.com");
+        assert_eq!(challenge.qop.as_deref(), Some("auth"));
+        let header = authorization(
+            &challenge,
+            "Mufasa",
+            "Circle Of Life",
+            "GET",
+            "/dir/index.html",
+            1,
+            "0a4f113b",
+        );
+        assert!(header.contains("response=\"6629fae49393a05397450978507c4ef1\""));
+        assert!(header.contains("nc=00000001"));
+
+        assert_eq!(
+            combine_prefix(
+                "2001:db8:1:2::".parse().unwrap(),
+                56,
+                "::1234:5678:9abc:def0".parse().unwrap()
+            ),
+            "2001:db8:1:0:1234:5678:9abc:def0"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+        );
+
+        // Local TR-064 endpoint, requires digest authorization
+        let address = serve_http(|request| {
+            assert!(request.starts_with("POST /upnp/control/wanipconnection1"));
+            if request.contains("Digest username=\"admin\"") {
+                assert!(request.contains("uri=\"/upnp/control/wanipconnection1\""));
+                http_ok(
+                    "<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\"><s:Body>\
+                     <u:GetExternalIPAddressResponse xmlns:u=\"urn:dslforum-org:service:WANIPConnection:1\">\
+                     <NewExternalIPAddress>198.51.100.8</NewExternalIPAddress>\
+                     </u:GetExternalIPAddressResponse></s:Body></s:Envelope>",
+                )
+            } else {
+                "HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Digest realm=\"HTTPS Access\", \
+                 nonce=\"B1C2D3E4F5A6B7C8\", algorithm=MD5, qop=\"auth\"\r\n\
+                 Content-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_string()
+            }
+        })
+        .await;
+
+        let configure: FritzboxConfigure = toml::from_str(&format!(
+            "route = \"http://{address}\"\nuser = \"admin\"\npassword = \"secret\"\ntimeout = 1"
+        ))
+        .unwrap();
+        let source = FritzboxIPSource::new(&configure, true, false).unwrap();
+        for _ in 0..2 {
+            let current = source.get_current_ip().await.unwrap();
+            assert_eq!(current.v4, Some("198.51.100.8".parse().unwrap()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_source() {
+        use crate::command::api::{parse_output, CommandConfigure, CommandIPSource};
+        use crate::configparser::IPSource;
+
+        assert_eq!(
+            parse_output("wan: 198.51.100.9\n2001:db8::9 up\n"),
+            vec![
+                "198.51.100.9".parse::<std::net::IpAddr>().unwrap(),
+                "2001:db8::9".parse().unwrap()
+            ]
+        );
+
+        let configure: CommandConfigure = toml::from_str(
+            r#"program = "sh"
+args = ["-c", "echo $ADDRESS; echo 2001:db8::9"]
+env = { ADDRESS = "198.51.100.9" }"#,
+        )
+        .unwrap();
+        let current = CommandIPSource::new(&configure, true, true)
+            .get_current_ip()
+            .await
+            .unwrap();
+        assert_eq!(current.v4, Some("198.51.100.9".parse().unwrap()));
+        assert_eq!(current.v6, Some("2001:db8::9".parse().unwrap()));
+
+        for command in [
+            r#"program = "sh"
+args = ["-c", "echo 198.51.100.9; exit 1"]"#,
+            r#"program = "sh"
+args = ["-c", "echo no address"]"#,
+            r#"program = "sleep"
+args = ["5"]
+timeout = 1"#,
+        ] {
+            let configure: CommandConfigure = toml::from_str(command).unwrap();
+            assert!(CommandIPSource::new(&configure, true, true)
+                .get_current_ip()
+                .await
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn test_aaaa_enables_ipv6() {
+        let content = r#"[account]
+
+[cloudflare]
+token = "114514"
+
+[[cloudflare.domain]]
+zone_id = "ca3d180a0c66ac16da45fad9f7674292"
+domains = [{ name = "a.example.com", types = ["AAAA"] }]
+
+[openwrt]
+enabled = false
+route = ""
+user = ""
+password = ""
+"#;
+        let mut configure: Configure = toml::from_str(content).unwrap();
+        configure.check_address_families().unwrap();
+        assert!(configure.get_account().get_ipv6());
+
+        let mut configure: Configure =
+            toml::from_str(&content.replace("[account]", "[account]\nipv6 = false")).unwrap();
+        assert!(configure.check_address_families().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_update_name_servers() {
+        use crate::configparser::{
+            CurrentIP, NameServer, RecordType, UpdateOutcome, UpdatedRecord,
+        };
+        use std::sync::Arc;
+
+        struct Succeed;
+        struct Fail;
+        struct Misconfigured;
+
+        #[async_trait::async_trait]
+        impl NameServer for Succeed {
+            fn name(&self) -> &str {
+                "succeed"
+            }
+
+            async fn update_dns_result(
+                &self,
+                current_ip: &CurrentIP,
+                _known: &[UpdatedRecord],
+            ) -> crate::error::Result<UpdateOutcome> {
+                Ok(UpdateOutcome::Updated(vec![UpdatedRecord::new(
+                    "a.example.com",
+                    RecordType::A,
+                    &current_ip.v4.unwrap().to_string(),
+                )]))
+            }
+        }
+
+        #[async_trait::async_trait]
+        impl NameServer for Fail {
+            fn name(&self) -> &str {
+                "fail"
+            }
+
+            async fn update_dns_result(
+                &self,
+                _current_ip: &CurrentIP,
+                _known: &[UpdatedRecord],
+            ) -> crate::error::Result<UpdateOutcome> {
+                Err(anyhow::anyhow!("upstream is down").into())
+            }
+        }
+
+        #[async_trait::async_trait]
+        impl NameServer for Misconfigured {
+            fn name(&self) -> &str {
+                "misconfigured"
+            }
+
+            async fn update_dns_result(
+                &self,
+                _current_ip: &CurrentIP,
+                _known: &[UpdatedRecord],
+            ) -> crate::error::Result<UpdateOutcome> {
+                Err(crate::error::Error::Config("zone not found".into()))
+            }
+        }
+
+        let name_servers: Vec<Arc<dyn NameServer>> =
+            vec![Arc::new(Succeed), Arc::new(Fail), Arc::new(Succeed)];
+        let current_ip = CurrentIP {
+            v4: Some("198.51.100.4".parse().unwrap()),
+            ..Default::default()
+        };
+        let mut states = vec![crate::FailureState::default(); name_servers.len()];
+        for _ in 0..2 {
+            for (index, records) in crate::update_name_servers(
+                &name_servers,
+                vec![(0, vec![]), (1, vec![]), (2, vec![])],
+                &current_ip,
+            )
+            .await
+            .unwrap()
+            {
+                match records {
+                    Some(records) => {
+                        assert_eq!(records.len(), 1);
+                        states[index].succeed();
+                    }
+                    None => states[index].fail(),
+                }
+            }
+        }
+        assert_eq!(
+            states
+                .iter()
+                .map(|state| state.attempts)
+                .collect::<Vec<_>>(),
+            vec![0, 2, 0]
+        );
+        assert!(states[1].is_failing());
+
+        // Configure error is returned to stop the process
+        let name_servers: Vec<Arc<dyn NameServer>> = vec![Arc::new(Fail), Arc::new(Misconfigured)];
+        assert!(matches!(
+            crate::update_name_servers(&name_servers, vec![(0, vec![]), (1, vec![])], &current_ip)
+                .await,
+            Err(crate::error::Error::Config(_))
+        ));
     }
 }
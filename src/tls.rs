@@ -0,0 +1,176 @@
+/*
+ ** Copyright (C) 2021-2024 KunoiSayami
+ **
+ ** This file is part of passive-DDNS and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use anyhow::anyhow;
+use log::error;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// TLS settings of router IP sources, usually served with self-signed certificate
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct TlsConfigure {
+    /// PEM bundle trusted in addition to system roots
+    ca_file: Option<PathBuf>,
+    /// SHA-256 fingerprints of accepted server certificates, hex with optional colons
+    pin_sha256: Option<Vec<String>>,
+    /// Accept any certificate
+    insecure: Option<bool>,
+}
+
+impl TlsConfigure {
+    /// Default is false
+    pub fn get_insecure(&self) -> bool {
+        self.insecure.unwrap_or(false)
+    }
+
+    /// Apply settings to client builder. If fingerprints are pinned, only pinned
+    /// certificates are accepted and other settings are ignored.
+    pub fn apply(
+        &self,
+        builder: reqwest::ClientBuilder,
+        name: &str,
+    ) -> anyhow::Result<reqwest::ClientBuilder> {
+        if let Some(pins) = self.pin_sha256.as_ref().filter(|pins| !pins.is_empty()) {
+            if self.ca_file.is_some() || self.get_insecure() {
+                error!("[{name}] Certificate is pinned, ca_file and insecure are ignored");
+            }
+            let verifier = PinnedCertVerifier::new(
+                pins.iter()
+                    .map(|pin| parse_fingerprint(pin))
+                    .collect::<anyhow::Result<_>>()?,
+            );
+            let config = rustls::ClientConfig::builder_with_provider(verifier.provider.clone())
+                .with_safe_default_protocol_versions()?
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(verifier))
+                .with_no_client_auth();
+            return Ok(builder.use_preconfigured_tls(config));
+        }
+        let mut builder = builder;
+        if let Some(ca_file) = &self.ca_file {
+            let bundle = std::fs::read(ca_file)
+                .map_err(|e| anyhow!("Read CA file {ca_file:?} error: {e}"))?;
+            for certificate in reqwest::Certificate::from_pem_bundle(&bundle)? {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+        if self.get_insecure() {
+            error!(
+                "[{name}] TLS certificate verification is DISABLED, anyone on the path \
+                 can impersonate the router. Pin its certificate with pin_sha256 instead."
+            );
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        Ok(builder)
+    }
+}
+
+/// Parse hex SHA-256 fingerprint, `AB:CD:...` and `abcd...` are both accepted
+pub(crate) fn parse_fingerprint(fingerprint: &str) -> anyhow::Result<[u8; 32]> {
+    let hex = fingerprint.replace(':', "");
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(anyhow!("Invalid SHA-256 fingerprint {fingerprint}"));
+    }
+    let mut digest = [0u8; 32];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|_| anyhow!("Invalid SHA-256 fingerprint {fingerprint}"))?;
+    }
+    Ok(digest)
+}
+
+/// Accept server only if its certificate matches one of pinned fingerprints,
+/// chain and hostname are not checked since certificate is trusted directly
+#[derive(Debug)]
+pub(crate) struct PinnedCertVerifier {
+    pins: Vec<[u8; 32]>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl PinnedCertVerifier {
+    pub(crate) fn new(pins: Vec<[u8; 32]>) -> Self {
+        Self {
+            pins,
+            provider: Arc::new(rustls::crypto::ring::default_provider()),
+        }
+    }
+
+    pub(crate) fn is_pinned(&self, certificate: &[u8]) -> bool {
+        let digest = ring::digest::digest(&ring::digest::SHA256, certificate);
+        self.pins.iter().any(|pin| pin == digest.as_ref())
+    }
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if self.is_pinned(end_entity) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "Server certificate does not match pinned fingerprint".into(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
@@ -0,0 +1,56 @@
+/*
+ ** Copyright (C) 2021-2024 KunoiSayami
+ **
+ ** This file is part of passive-DDNS and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use anyhow::anyhow;
+use log::debug;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+const RETRANSMIT_TIMES: usize = 3;
+
+/// Send `request` to `server` until a response passing `accept` is received,
+/// retransmit if nothing is accepted within `timeout`
+pub(crate) async fn exchange(
+    server: SocketAddr,
+    request: &[u8],
+    timeout: Duration,
+    accept: impl Fn(&[u8]) -> bool,
+) -> anyhow::Result<Vec<u8>> {
+    let bind_address: SocketAddr = if server.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(bind_address).await?;
+    socket.connect(server).await?;
+    let mut buffer = [0u8; 1232];
+    for _ in 0..RETRANSMIT_TIMES {
+        socket.send(request).await?;
+        let deadline = tokio::time::Instant::now() + timeout;
+        while let Ok(size) = tokio::time::timeout_at(deadline, socket.recv(&mut buffer)).await {
+            let response = &buffer[..size?];
+            if accept(response) {
+                return Ok(response.to_vec());
+            }
+        }
+        debug!("Request to {server} timeout, retransmit");
+    }
+    Err(anyhow!("{server} no response"))
+}